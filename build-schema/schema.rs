@@ -3,7 +3,7 @@ use std::path::Path;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use affiliate_swap::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
+use affiliate_swap::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, SudoMsg};
 
 fn main() {
     let out_dir = "schema";
@@ -12,4 +12,6 @@ fn main() {
     export_schema(&schema_for!(InstantiateMsg), Path::new(out_dir));
     export_schema(&schema_for!(ExecuteMsg), Path::new(out_dir));
     export_schema(&schema_for!(QueryMsg), Path::new(out_dir));
+    export_schema(&schema_for!(MigrateMsg), Path::new(out_dir));
+    export_schema(&schema_for!(SudoMsg), Path::new(out_dir));
 }
@@ -3,19 +3,34 @@ use cosmwasm_std::entry_point;
 use cosmwasm_std::{
     to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Reply, Response, StdResult,
 };
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
+use semver::Version;
 
 use crate::error::ContractError;
-use crate::execute::SWAP_REPLY_ID;
 use crate::execute::{
-    handle_swap_reply, proxy_swap_with_fee, transfer_ownership, update_affiliate,
+    accept_ownership, batch_proxy_swap, cancel_ownership_transfer, claim_fees, claim_recoverable,
+    convert_fees, handle_convert_reply, handle_ibc_transfer_reply, handle_post_swap_reply,
+    handle_swap_reply, ibc_hooks_proxy_swap, ibc_lifecycle_complete, pause, proxy_swap_with_fee,
+    register_affiliate, remove_affiliate, renounce_ownership, set_conversion_route,
+    set_fee_override, set_fee_withdraw_address, set_guardian, set_settlement_denom, set_twap_guard,
+    transfer_ownership, unpause, update_affiliate, update_config, update_route_lists,
 };
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
-use crate::query::query_config;
-use crate::state::{Config, CONFIG};
+use crate::execute::{
+    SwapOptions, CONVERT_REPLY_ID_START, IBC_TRANSFER_REPLY_ID_START, POST_SWAP_REPLY_ID_START,
+    REPLY_ID_RANGE, SWAP_REPLY_ID_START,
+};
+use crate::migrations::migrate_from;
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg, SudoMsg};
+use crate::query::{
+    query_affiliate, query_affiliate_stats, query_affiliates, query_config, query_conversion_route,
+    query_fee_override, query_fee_overrides, query_global_stats, query_ownership,
+    query_pause_status, query_pending_fees, query_recoverable, query_route_lists,
+    query_simulate_proxy_swap, query_swap, query_swaps_by_affiliate, query_swaps_by_sender,
+};
+use crate::state::{Config, FeeSide, CONFIG, DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS};
 
-const CONTRACT_NAME: &str = "crates.io:affiliate-swap";
-const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const CONTRACT_NAME: &str = "crates.io:affiliate-swap";
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[cfg_attr(not(feature = "imported"), entry_point)]
 pub fn instantiate(
@@ -29,12 +44,21 @@ pub fn instantiate(
     if msg.affiliate_bps > 10_000 {
         return Err(ContractError::InvalidAffiliateBps {});
     }
-    let owner = deps.api.addr_validate(&msg.owner)?;
+    let owner = Some(deps.api.addr_validate(&msg.owner)?);
     let affiliate_addr = deps.api.addr_validate(&msg.affiliate_addr)?;
     let cfg = Config {
         owner,
         affiliate_addr,
         affiliate_bps: msg.affiliate_bps,
+        enforce_affiliate_bps: msg.enforce_affiliate_bps,
+        strict_funds: msg.strict_funds,
+        affiliate_recipients: vec![],
+        affiliate_fee_side: FeeSide::Input,
+        guardian: None,
+        accrue_fees: false,
+        conversion_max_slippage_bps: DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS,
+        balance_delta_accounting: false,
+        twap_guard: None,
     };
     CONFIG.save(deps.storage, &cfg)?;
     Ok(Response::new().add_attribute("method", "instantiate"))
@@ -48,27 +72,241 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::ProxySwapWithFee { swap } => proxy_swap_with_fee(deps, env, info, swap),
+        ExecuteMsg::ProxySwapWithFee {
+            swap,
+            affiliate_id,
+            recipient,
+            ibc_forward,
+            post_swap_action,
+            deadline,
+        } => proxy_swap_with_fee(
+            deps,
+            env,
+            info,
+            swap,
+            SwapOptions {
+                affiliate_id,
+                recipient,
+                ibc_forward,
+                post_swap_action,
+                deadline,
+                fee_payer: None,
+            },
+        ),
+        ExecuteMsg::RegisterAffiliate {
+            id,
+            affiliate_addr,
+            affiliate_bps,
+            recipients,
+            fee_side,
+        } => register_affiliate(
+            deps,
+            info,
+            id,
+            affiliate_addr,
+            affiliate_bps,
+            recipients,
+            fee_side,
+        ),
         ExecuteMsg::UpdateAffiliate {
+            id,
+            affiliate_addr,
+            affiliate_bps,
+            recipients,
+            fee_side,
+        } => update_affiliate(
+            deps,
+            info,
+            id,
             affiliate_addr,
             affiliate_bps,
-        } => update_affiliate(deps, info, affiliate_addr, affiliate_bps),
-        ExecuteMsg::TransferOwnership { new_owner } => transfer_ownership(deps, info, new_owner),
+            recipients,
+            fee_side,
+        ),
+        ExecuteMsg::RemoveAffiliate { id } => remove_affiliate(deps, info, id),
+        ExecuteMsg::SetFeeOverride { address, bps } => set_fee_override(deps, info, address, bps),
+        ExecuteMsg::TransferOwnership { new_owner, expiry } => {
+            transfer_ownership(deps, env, info, new_owner, expiry)
+        }
+        ExecuteMsg::AcceptOwnership {} => accept_ownership(deps, env, info),
+        ExecuteMsg::CancelOwnershipTransfer {} => cancel_ownership_transfer(deps, info),
+        ExecuteMsg::RenounceOwnership {} => renounce_ownership(deps, info),
+        ExecuteMsg::UpdateConfig {
+            strict_funds,
+            enforce_affiliate_bps,
+            accrue_fees,
+            conversion_max_slippage_bps,
+            balance_delta_accounting,
+        } => update_config(
+            deps,
+            info,
+            strict_funds,
+            enforce_affiliate_bps,
+            accrue_fees,
+            conversion_max_slippage_bps,
+            balance_delta_accounting,
+        ),
+        ExecuteMsg::SetFeeWithdrawAddress { address } => {
+            set_fee_withdraw_address(deps, info, address)
+        }
+        ExecuteMsg::ClaimFees { affiliate, denoms } => claim_fees(deps, info, affiliate, denoms),
+        ExecuteMsg::SetSettlementDenom { denom } => set_settlement_denom(deps, info, denom),
+        ExecuteMsg::SetConversionRoute {
+            denom_in,
+            denom_out,
+            route,
+        } => set_conversion_route(deps, info, denom_in, denom_out, route),
+        ExecuteMsg::ConvertFees { start_after, limit } => {
+            convert_fees(deps, env, info, start_after, limit)
+        }
+        ExecuteMsg::UpdateRouteLists {
+            allowed_pools,
+            denied_pools,
+            allowed_denoms,
+            denied_denoms,
+        } => update_route_lists(
+            deps,
+            info,
+            allowed_pools,
+            denied_pools,
+            allowed_denoms,
+            denied_denoms,
+        ),
+        ExecuteMsg::SetTwapGuard { guard } => set_twap_guard(deps, info, guard),
+        ExecuteMsg::SetGuardian { guardian } => set_guardian(deps, info, guardian),
+        ExecuteMsg::Pause { operations } => pause(deps, info, operations),
+        ExecuteMsg::Unpause { operations } => unpause(deps, info, operations),
+        ExecuteMsg::BatchProxySwap {
+            swaps,
+            affiliate_id,
+        } => batch_proxy_swap(deps, env, info, swaps, affiliate_id),
+        ExecuteMsg::IbcHooksProxySwap {
+            swap,
+            affiliate_id,
+            ibc_forward,
+            fallback_address,
+        } => ibc_hooks_proxy_swap(
+            deps,
+            env,
+            info,
+            swap,
+            affiliate_id,
+            ibc_forward,
+            fallback_address,
+        ),
+        ExecuteMsg::ClaimRecoverable {} => claim_recoverable(deps, info),
     }
 }
 
 #[cfg_attr(not(feature = "imported"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::Config {} => to_json_binary(&query_config(deps)?),
+        QueryMsg::Ownership {} => to_json_binary(&query_ownership(deps)?),
+        QueryMsg::PauseStatus {} => to_json_binary(&query_pause_status(deps)?),
+        QueryMsg::RouteLists {} => to_json_binary(&query_route_lists(deps)?),
+        QueryMsg::Affiliate { id } => to_json_binary(&query_affiliate(deps, id)?),
+        QueryMsg::Affiliates { start_after, limit } => {
+            to_json_binary(&query_affiliates(deps, start_after, limit)?)
+        }
+        QueryMsg::FeeOverride { address } => to_json_binary(&query_fee_override(deps, address)?),
+        QueryMsg::FeeOverrides { start_after, limit } => {
+            to_json_binary(&query_fee_overrides(deps, start_after, limit)?)
+        }
+        QueryMsg::Swap { id } => to_json_binary(&query_swap(deps, id)?),
+        QueryMsg::SwapsBySender {
+            sender,
+            start_after,
+            limit,
+        } => to_json_binary(&query_swaps_by_sender(deps, sender, start_after, limit)?),
+        QueryMsg::SwapsByAffiliate {
+            affiliate,
+            start_after,
+            limit,
+        } => to_json_binary(&query_swaps_by_affiliate(
+            deps,
+            affiliate,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::AffiliateStats {
+            affiliate,
+            start_after,
+            limit,
+        } => to_json_binary(&query_affiliate_stats(deps, affiliate, start_after, limit)?),
+        QueryMsg::GlobalStats { start_after, limit } => {
+            to_json_binary(&query_global_stats(deps, start_after, limit)?)
+        }
+        QueryMsg::PendingFees { affiliate } => {
+            to_json_binary(&query_pending_fees(deps, affiliate)?)
+        }
+        QueryMsg::ConversionRoute {
+            denom_in,
+            denom_out,
+        } => to_json_binary(&query_conversion_route(deps, denom_in, denom_out)?),
+        QueryMsg::Recoverable { address } => to_json_binary(&query_recoverable(deps, address)?),
+        QueryMsg::SimulateProxySwap {
+            swap,
+            gross_funds,
+            affiliate_id,
+            sender,
+        } => to_json_binary(&query_simulate_proxy_swap(
+            deps,
+            env,
+            swap,
+            gross_funds,
+            affiliate_id,
+            sender,
+        )?),
+    }
+}
+
+#[cfg_attr(not(feature = "imported"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let nonce = msg.id % REPLY_ID_RANGE;
+    match msg.id - nonce {
+        SWAP_REPLY_ID_START => handle_swap_reply(deps, env, nonce, msg),
+        IBC_TRANSFER_REPLY_ID_START => handle_ibc_transfer_reply(deps, nonce, msg),
+        POST_SWAP_REPLY_ID_START => handle_post_swap_reply(deps, nonce, msg),
+        CONVERT_REPLY_ID_START => handle_convert_reply(deps, nonce, msg),
+        _ => Ok(Response::new()),
+    }
+}
+
+#[cfg_attr(not(feature = "imported"), entry_point)]
+pub fn sudo(deps: DepsMut, _env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::IbcLifecycleComplete(lifecycle) => ibc_lifecycle_complete(deps, lifecycle),
     }
 }
 
+fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version)
+        .map_err(|e| cosmwasm_std::StdError::generic_err(format!("invalid version: {e}")).into())
+}
+
 #[cfg_attr(not(feature = "imported"), entry_point)]
-pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
-    if msg.id == SWAP_REPLY_ID {
-        handle_swap_reply(deps, msg)
-    } else {
-        Ok(Response::new())
+pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::InvalidContractName {
+            name: stored.contract,
+        });
+    }
+    let stored_version = parse_version(&stored.version)?;
+    let current_version = parse_version(CONTRACT_VERSION)?;
+    if stored_version > current_version {
+        return Err(ContractError::CannotDowngrade {
+            stored: stored.version,
+            current: CONTRACT_VERSION.to_string(),
+        });
     }
+
+    migrate_from(deps.storage, &stored_version)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION))
 }
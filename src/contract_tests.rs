@@ -1,13 +1,66 @@
-use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-use cosmwasm_std::{from_json, BankMsg, Coin, CosmosMsg, Uint128};
+use std::collections::HashMap;
 
-use crate::contract::{execute, instantiate, query, reply};
-use crate::execute::SWAP_REPLY_ID;
-use crate::msg::{ExecuteMsg, InstantiateMsg, ProxySwap, QueryMsg};
+use cosmwasm_std::testing::{
+    mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+};
+use cosmwasm_std::{
+    from_json, to_json_binary, BankMsg, Binary, Coin, ContractResult, CosmosMsg, Empty, OwnedDeps,
+    Querier, QuerierResult, QueryRequest, SubMsg, SystemResult, Uint128, WasmMsg,
+};
+
+use crate::contract::{execute, instantiate, migrate, query, reply, sudo, CONTRACT_NAME};
+use crate::error::ContractError;
+use crate::execute::{IBC_TRANSFER_REPLY_ID_START, POST_SWAP_REPLY_ID_START, SWAP_REPLY_ID_START};
+use crate::msg::{
+    DenomStatsResponse, ExecuteMsg, FeeOverridesResponse, FeeRecipient, FeeSide, IbcForward,
+    IbcLifecycleComplete, InstantiateMsg, MigrateMsg, PausableOperation, PendingFeesResponse,
+    PostSwapAction, ProxySwap, QueryMsg, RecoverableResponse, StatsResponse, SudoMsg,
+    SwapRecordResponse, SwapsResponse, TwapGuard,
+};
+use osmosis_std::types::ibc::applications::transfer::v1::{MsgTransfer, MsgTransferResponse};
 use osmosis_std::types::osmosis::poolmanager::v1beta1::{
-    MsgSplitRouteSwapExactAmountInResponse, MsgSwapExactAmountInResponse, SwapAmountInRoute,
-    SwapAmountInSplitRoute,
+    EstimateSwapExactAmountInResponse, EstimateSwapExactAmountOutResponse,
+    MsgSplitRouteSwapExactAmountInResponse, MsgSplitRouteSwapExactAmountOutResponse,
+    MsgSwapExactAmountIn, MsgSwapExactAmountInResponse, MsgSwapExactAmountOutResponse,
+    SwapAmountInRoute, SwapAmountInSplitRoute, SwapAmountOutRoute, SwapAmountOutSplitRoute,
 };
+use osmosis_std::types::osmosis::twap::v1beta1::ArithmeticTwapToNowResponse;
+
+/// Answers stargate queries with canned JSON responses keyed by path.
+struct StargateQuerier {
+    base: MockQuerier,
+    responses: HashMap<String, Binary>,
+}
+
+impl Querier for StargateQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        if let Ok(QueryRequest::Stargate { path, .. }) =
+            from_json::<QueryRequest<Empty>>(bin_request)
+        {
+            if let Some(resp) = self.responses.get(&path) {
+                return SystemResult::Ok(ContractResult::Ok(resp.clone()));
+            }
+        }
+        self.base.raw_query(bin_request)
+    }
+}
+
+fn stargate_dependencies(
+    responses: Vec<(&str, Binary)>,
+) -> OwnedDeps<MockStorage, MockApi, StargateQuerier> {
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: StargateQuerier {
+            base: MockQuerier::default(),
+            responses: responses
+                .into_iter()
+                .map(|(path, resp)| (path.to_string(), resp))
+                .collect(),
+        },
+        custom_query_type: Default::default(),
+    }
+}
 
 fn mock_instantiate<S, A, Q>(deps: &mut cosmwasm_std::OwnedDeps<S, A, Q>)
 where
@@ -19,6 +72,8 @@ where
         owner: "owner".to_string(),
         affiliate_addr: "affiliate".to_string(),
         affiliate_bps: 250, // 2.5%
+        enforce_affiliate_bps: false,
+        strict_funds: false,
     };
     let info = mock_info("owner", &[]);
     instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
@@ -49,6 +104,11 @@ fn test_proxy_single() {
             token_in: Coin::new(1000, "uion"),
             token_out_min_amount: Uint128::new(1),
         },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
     };
     // Gross funds include affiliate fee (e.g., 2.5% of 1000 = 25)
     let info = mock_info("trader", &[Coin::new(1025, "uion")]);
@@ -64,7 +124,7 @@ fn test_proxy_single() {
         }
         _ => panic!("expected BankMsg::Send for affiliate payout"),
     }
-    assert_eq!(resp.messages[1].id, SWAP_REPLY_ID);
+    assert_eq!(resp.messages[1].id, SWAP_REPLY_ID_START);
 
     let resp_msg = MsgSwapExactAmountInResponse {
         token_out_amount: "1000".to_string(),
@@ -72,7 +132,7 @@ fn test_proxy_single() {
     let mut data = Vec::new();
     prost::Message::encode(&resp_msg, &mut data).unwrap();
     let reply_msg = cosmwasm_std::Reply {
-        id: SWAP_REPLY_ID,
+        id: SWAP_REPLY_ID_START,
         result: cosmwasm_std::SubMsgResult::Ok(cosmwasm_std::SubMsgResponse {
             data: Some(cosmwasm_std::Binary::from(data)),
             events: vec![],
@@ -100,6 +160,11 @@ fn test_proxy_split() {
             token_in_denom: "uion".to_string(),
             token_out_min_amount: Uint128::new(1),
         },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
     };
     // Gross funds include affiliate difference over the total route input (25)
     let info = mock_info("trader", &[Coin::new(1025, "uion")]);
@@ -115,7 +180,7 @@ fn test_proxy_split() {
         }
         _ => panic!("expected BankMsg::Send for affiliate payout"),
     }
-    assert_eq!(resp.messages[1].id, SWAP_REPLY_ID);
+    assert_eq!(resp.messages[1].id, SWAP_REPLY_ID_START);
 
     let resp_msg = MsgSplitRouteSwapExactAmountInResponse {
         token_out_amount: "1000".to_string(),
@@ -123,7 +188,7 @@ fn test_proxy_split() {
     let mut data = Vec::new();
     prost::Message::encode(&resp_msg, &mut data).unwrap();
     let reply_msg = cosmwasm_std::Reply {
-        id: SWAP_REPLY_ID,
+        id: SWAP_REPLY_ID_START,
         result: cosmwasm_std::SubMsgResult::Ok(cosmwasm_std::SubMsgResponse {
             data: Some(cosmwasm_std::Binary::from(data)),
             events: vec![],
@@ -141,6 +206,8 @@ fn test_single_difference_fee_path() {
         owner: "owner".to_string(),
         affiliate_addr: "affiliate".to_string(),
         affiliate_bps: 30,
+        enforce_affiliate_bps: false,
+        strict_funds: false,
     };
     let info = mock_info("owner", &[]);
     instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
@@ -155,6 +222,11 @@ fn test_single_difference_fee_path() {
             token_in: Coin::new(99_700, "uion"),
             token_out_min_amount: Uint128::new(1),
         },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
     };
     // Gross = 100_000; affiliate = 300; net token_in = 99_700
     let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
@@ -178,6 +250,8 @@ fn test_split_difference_fee_path() {
         owner: "owner".to_string(),
         affiliate_addr: "affiliate".to_string(),
         affiliate_bps: 30,
+        enforce_affiliate_bps: false,
+        strict_funds: false,
     };
     let info = mock_info("owner", &[]);
     instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
@@ -195,6 +269,11 @@ fn test_split_difference_fee_path() {
             token_in_denom: "uion".to_string(),
             token_out_min_amount: Uint128::new(1),
         },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
     };
     // Gross = 100_000; affiliate = 300; net total_in = 99_700
     let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
@@ -209,3 +288,2401 @@ fn test_split_difference_fee_path() {
         _ => panic!("expected BankMsg::Send for affiliate payout"),
     }
 }
+
+fn instantiate_enforced<S, A, Q>(deps: &mut cosmwasm_std::OwnedDeps<S, A, Q>, affiliate_bps: u16)
+where
+    S: cosmwasm_std::Storage,
+    A: cosmwasm_std::Api,
+    Q: cosmwasm_std::Querier,
+{
+    let msg = InstantiateMsg {
+        owner: "owner".to_string(),
+        affiliate_addr: "affiliate".to_string(),
+        affiliate_bps,
+        enforce_affiliate_bps: true,
+        strict_funds: false,
+    };
+    let info = mock_info("owner", &[]);
+    instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
+}
+
+fn single_swap_payload(token_in: Coin) -> ProxySwap {
+    ProxySwap::SwapExactAmountIn {
+        routes: vec![SwapAmountInRoute {
+            pool_id: 1,
+            token_out_denom: "uosmo".to_string(),
+        }],
+        token_in,
+        token_out_min_amount: Uint128::new(1),
+    }
+}
+
+fn single_swap(token_in: Coin) -> ExecuteMsg {
+    ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(token_in),
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    }
+}
+
+#[test]
+fn test_update_config_toggles_enforced_fee() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    register(deps.as_mut(), "wallet", "wallet_fees", 100);
+
+    let enforce = |enforce_affiliate_bps| ExecuteMsg::UpdateConfig {
+        strict_funds: None,
+        enforce_affiliate_bps,
+        accrue_fees: None,
+        conversion_max_slippage_bps: None,
+        balance_delta_accounting: None,
+    };
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        enforce(Some(true)),
+    )
+    .unwrap();
+    let bin = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let resp: crate::msg::ConfigResponse = from_json(bin).unwrap();
+    assert!(resp.enforce_affiliate_bps);
+
+    // Applies to registered partners too: 1000 uion at 1% must net 990
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: Some("wallet".to_string()),
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1000, "uion")]);
+    let err = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap_err();
+    assert!(matches!(err, ContractError::AffiliateFeeMismatch { .. }));
+
+    // Omitting the field leaves enforcement on
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        enforce(None),
+    )
+    .unwrap();
+    let err = execute(deps.as_mut(), mock_env(), info.clone(), msg.clone()).unwrap_err();
+    assert!(matches!(err, ContractError::AffiliateFeeMismatch { .. }));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        enforce(Some(false)),
+    )
+    .unwrap();
+    execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+}
+
+#[test]
+fn test_enforced_fee_rejects_mismatch() {
+    let mut deps = mock_dependencies();
+    instantiate_enforced(&mut deps, 30);
+
+    // Caller tries to pay no fee at all
+    let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(100_000, "uion")),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::AffiliateFeeMismatch {
+            expected: Uint128::new(99_700),
+            actual: Uint128::new(100_000),
+        }
+    );
+
+    // Caller overpays the fee
+    let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(90_000, "uion")),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::AffiliateFeeMismatch { .. }));
+
+    // Matching payload is accepted
+    let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(99_700, "uion")),
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 2);
+}
+
+#[test]
+fn test_enforced_fee_minimum_one_unit() {
+    let mut deps = mock_dependencies();
+    instantiate_enforced(&mut deps, 30);
+
+    // 30 bps of 100 rounds down to zero, so the minimum of 1 applies
+    let info = mock_info("trader", &[Coin::new(100, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(99, "uion")),
+    )
+    .unwrap();
+    match &resp.messages[0].msg {
+        CosmosMsg::Bank(BankMsg::Send { amount, .. }) => {
+            assert_eq!(amount[0].amount, Uint128::new(1));
+        }
+        _ => panic!("expected BankMsg::Send for affiliate payout"),
+    }
+
+    // Minimum fee consumes the whole input: only the affiliate transfer occurs
+    let info = mock_info("trader", &[Coin::new(1, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(0, "uion")),
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 1);
+}
+
+fn encoded_reply<M: prost::Message>(resp_msg: M) -> cosmwasm_std::Reply {
+    let mut data = Vec::new();
+    prost::Message::encode(&resp_msg, &mut data).unwrap();
+    cosmwasm_std::Reply {
+        id: SWAP_REPLY_ID_START,
+        result: cosmwasm_std::SubMsgResult::Ok(cosmwasm_std::SubMsgResponse {
+            data: Some(cosmwasm_std::Binary::from(data)),
+            events: vec![],
+        }),
+    }
+}
+
+#[test]
+fn test_proxy_exact_out_refunds_unused_input() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SwapExactAmountOut {
+            routes: vec![SwapAmountOutRoute {
+                pool_id: 1,
+                token_in_denom: "uion".to_string(),
+            }],
+            token_in_max_amount: Uint128::new(1000),
+            token_out: Coin::new(500, "uosmo"),
+        },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    // Fee (25) is taken on the max input
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(resp.messages.len(), 2);
+    match &resp.messages[0].msg {
+        CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+            assert_eq!(to_address, "affiliate");
+            assert_eq!(amount, &vec![Coin::new(25, "uion")]);
+        }
+        _ => panic!("expected BankMsg::Send for affiliate payout"),
+    }
+    assert_eq!(resp.messages[1].id, SWAP_REPLY_ID_START);
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountOutResponse {
+        token_in_amount: "880".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    assert_eq!(
+        resp.messages
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "trader".to_string(),
+                amount: vec![Coin::new(500, "uosmo")],
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "trader".to_string(),
+                amount: vec![Coin::new(120, "uion")],
+            }),
+        ]
+    );
+    let data: crate::msg::SwapResponse = from_json(resp.data.unwrap()).unwrap();
+    assert_eq!(data.amount_sent_to_user, Uint128::new(500));
+    assert_eq!(data.token_in_refunded, Uint128::new(120));
+}
+
+#[test]
+fn test_proxy_split_exact_out() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let route = |token_out_amount: &str| SwapAmountOutSplitRoute {
+        pools: vec![SwapAmountOutRoute {
+            pool_id: 1,
+            token_in_denom: "uion".to_string(),
+        }],
+        token_out_amount: token_out_amount.to_string(),
+    };
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SplitRouteSwapExactAmountOut {
+            routes: vec![route("300"), route("200")],
+            token_out_denom: "uosmo".to_string(),
+            token_in_max_amount: Uint128::new(1000),
+        },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(resp.messages.len(), 2);
+
+    // Whole max input spent: no refund, output is the summed route amounts
+    let reply_msg = encoded_reply(MsgSplitRouteSwapExactAmountOutResponse {
+        token_in_amount: "1000".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    assert_eq!(resp.messages.len(), 1);
+    let data: crate::msg::SwapResponse = from_json(resp.data.unwrap()).unwrap();
+    assert_eq!(data.amount_sent_to_user, Uint128::new(500));
+    assert!(data.token_in_refunded.is_zero());
+}
+
+#[test]
+fn test_proxy_refunds_foreign_denoms() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let info = mock_info(
+        "trader",
+        &[
+            Coin::new(1025, "uion"),
+            Coin::new(7, "uatom"),
+            Coin::new(3, "uosmo"),
+        ],
+    );
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 3);
+    assert_eq!(
+        resp.messages[1].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "trader".to_string(),
+            amount: vec![Coin::new(7, "uatom"), Coin::new(3, "uosmo")],
+        })
+    );
+    assert!(resp
+        .attributes
+        .iter()
+        .any(|a| a.key == "refunds" && a.value == "7uatom,3uosmo"));
+    assert_eq!(resp.messages[2].id, SWAP_REPLY_ID_START);
+
+    // Funds attached to admin messages are returned as well
+    let info = mock_info("owner", &[Coin::new(5, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 1);
+}
+
+#[test]
+fn test_strict_funds_rejects_extra_denoms() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let info = mock_info("trader", &[]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        ExecuteMsg::UpdateConfig {
+            strict_funds: Some(true),
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+
+    let info = mock_info("owner", &[]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        ExecuteMsg::UpdateConfig {
+            strict_funds: Some(true),
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap();
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion"), Coin::new(7, "uatom")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::UnexpectedFunds {
+            denom: "uatom".to_string()
+        }
+    );
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 2);
+}
+
+fn register(deps: cosmwasm_std::DepsMut, id: &str, affiliate_addr: &str, affiliate_bps: u16) {
+    let msg = ExecuteMsg::RegisterAffiliate {
+        id: id.to_string(),
+        affiliate_addr: affiliate_addr.to_string(),
+        affiliate_bps,
+        recipients: None,
+        fee_side: None,
+    };
+    execute(deps, mock_env(), mock_info("owner", &[]), msg).unwrap();
+}
+
+#[test]
+fn test_affiliate_registry_selects_partner() {
+    let mut deps = mock_dependencies();
+    instantiate_enforced(&mut deps, 250);
+    register(deps.as_mut(), "wallet", "partner", 30);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("trader", &[]),
+        ExecuteMsg::RegisterAffiliate {
+            id: "other".to_string(),
+            affiliate_addr: "partner".to_string(),
+            affiliate_bps: 30,
+            recipients: None,
+            fee_side: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::RegisterAffiliate {
+            id: "wallet".to_string(),
+            affiliate_addr: "partner".to_string(),
+            affiliate_bps: 30,
+            recipients: None,
+            fee_side: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::AffiliateAlreadyExists {
+            id: "wallet".to_string()
+        }
+    );
+
+    // Partner rate (30 bps) applies instead of the default 250 bps
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(99_700, "uion")),
+        affiliate_id: Some("wallet".to_string()),
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "partner".to_string(),
+            amount: vec![Coin::new(300, "uion")],
+        })
+    );
+
+    let msg = ExecuteMsg::RemoveAffiliate {
+        id: "wallet".to_string(),
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(99_700, "uion")),
+        affiliate_id: Some("wallet".to_string()),
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(100_000, "uion")]);
+    let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::AffiliateNotFound {
+            id: "wallet".to_string()
+        }
+    );
+}
+
+#[test]
+fn test_affiliates_query_pagination() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    register(deps.as_mut(), "a", "partner_a", 10);
+    register(deps.as_mut(), "b", "partner_b", 20);
+    register(deps.as_mut(), "c", "partner_c", 30);
+
+    let msg = ExecuteMsg::UpdateAffiliate {
+        id: Some("b".to_string()),
+        affiliate_addr: "partner_b2".to_string(),
+        affiliate_bps: 25,
+        recipients: None,
+        fee_side: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+    let bin = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Affiliate {
+            id: "b".to_string(),
+        },
+    )
+    .unwrap();
+    let resp: crate::msg::AffiliateResponse = from_json(bin).unwrap();
+    assert_eq!(resp.affiliate_addr, "partner_b2");
+    assert_eq!(resp.affiliate_bps, 25);
+
+    // Default affiliate is untouched by registry updates
+    let bin = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let resp: crate::msg::ConfigResponse = from_json(bin).unwrap();
+    assert_eq!(resp.affiliate_addr, "affiliate");
+
+    let bin = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Affiliates {
+            start_after: None,
+            limit: Some(2),
+        },
+    )
+    .unwrap();
+    let resp: crate::msg::AffiliatesResponse = from_json(bin).unwrap();
+    let ids: Vec<_> = resp.affiliates.iter().map(|a| a.id.as_str()).collect();
+    assert_eq!(ids, vec!["a", "b"]);
+
+    let bin = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Affiliates {
+            start_after: Some("b".to_string()),
+            limit: None,
+        },
+    )
+    .unwrap();
+    let resp: crate::msg::AffiliatesResponse = from_json(bin).unwrap();
+    let ids: Vec<_> = resp.affiliates.iter().map(|a| a.id.as_str()).collect();
+    assert_eq!(ids, vec!["c"]);
+}
+
+#[test]
+fn test_fee_split_across_weighted_recipients() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let recipient = |addr: &str, weight: u16| FeeRecipient {
+        addr: addr.to_string(),
+        weight,
+    };
+    let update = |recipients: Option<Vec<FeeRecipient>>| ExecuteMsg::UpdateAffiliate {
+        id: None,
+        affiliate_addr: "affiliate".to_string(),
+        affiliate_bps: 250,
+        recipients,
+        fee_side: None,
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        update(Some(vec![
+            recipient("alice", 5_000),
+            recipient("bob", 4_000),
+        ])),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::InvalidFeeRecipients {});
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        update(Some(vec![
+            recipient("alice", 3_334),
+            recipient("bob", 3_333),
+            recipient("carol", 3_333),
+        ])),
+    )
+    .unwrap();
+
+    // Fee of 25 splits 8/8/8 with the leftover unit going to the first recipient
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    let sends: Vec<_> = resp.messages[..3]
+        .iter()
+        .map(|m| match &m.msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                (to_address.as_str(), amount[0].amount.u128())
+            }
+            _ => panic!("expected BankMsg::Send for affiliate payout"),
+        })
+        .collect();
+    assert_eq!(sends, vec![("alice", 9), ("bob", 8), ("carol", 8)]);
+    assert_eq!(resp.messages[3].id, SWAP_REPLY_ID_START);
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "1000".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    let data: crate::msg::SwapResponse = from_json(resp.data.unwrap()).unwrap();
+    let payouts: Vec<_> = data
+        .affiliate_payouts
+        .iter()
+        .map(|p| (p.recipient.as_str(), p.amount.amount.u128()))
+        .collect();
+    assert_eq!(payouts, vec![("alice", 9), ("bob", 8), ("carol", 8)]);
+
+    // Omitting recipients keeps the split; an empty list clears it
+    let recipients = |deps: &OwnedDeps<_, _, _>| -> Vec<FeeRecipient> {
+        let bin = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+        from_json::<crate::msg::ConfigResponse>(bin)
+            .unwrap()
+            .affiliate_recipients
+    };
+    let owner = mock_info("owner", &[]);
+    execute(deps.as_mut(), mock_env(), owner.clone(), update(None)).unwrap();
+    assert_eq!(recipients(&deps).len(), 3);
+    execute(deps.as_mut(), mock_env(), owner, update(Some(vec![]))).unwrap();
+    assert!(recipients(&deps).is_empty());
+}
+
+#[test]
+fn test_update_affiliate_keeps_fee_side() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let msg = ExecuteMsg::RegisterAffiliate {
+        id: "usdc".to_string(),
+        affiliate_addr: "partner".to_string(),
+        affiliate_bps: 100,
+        recipients: None,
+        fee_side: Some(FeeSide::Output),
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+    let msg = ExecuteMsg::UpdateAffiliate {
+        id: Some("usdc".to_string()),
+        affiliate_addr: "partner".to_string(),
+        affiliate_bps: 50,
+        recipients: None,
+        fee_side: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+    let bin = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::Affiliate {
+            id: "usdc".to_string(),
+        },
+    )
+    .unwrap();
+    let resp: crate::msg::AffiliateResponse = from_json(bin).unwrap();
+    assert_eq!(resp.affiliate_bps, 50);
+    assert_eq!(resp.fee_side, FeeSide::Output);
+}
+
+#[test]
+fn test_output_side_fee() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let msg = ExecuteMsg::RegisterAffiliate {
+        id: "usdc".to_string(),
+        affiliate_addr: "partner".to_string(),
+        affiliate_bps: 100,
+        recipients: None,
+        fee_side: Some(FeeSide::Output),
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SwapExactAmountIn {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1,
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_in: Coin::new(1000, "uion"),
+            token_out_min_amount: Uint128::new(990),
+        },
+        affiliate_id: Some("usdc".to_string()),
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    // No input fee: the whole input is swapped
+    let info = mock_info("trader", &[Coin::new(1000, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(resp.messages.len(), 1);
+    match &resp.messages[0].msg {
+        CosmosMsg::Stargate { value, .. } => {
+            let swap: osmosis_std::types::osmosis::poolmanager::v1beta1::MsgSwapExactAmountIn =
+                prost::Message::decode(value.as_slice()).unwrap();
+            // 1000 - 1% = 990, so poolmanager must deliver at least 1000
+            assert_eq!(swap.token_out_min_amount, "1000");
+        }
+        _ => panic!("expected stargate swap message"),
+    }
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "2000".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    assert_eq!(
+        resp.messages
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "partner".to_string(),
+                amount: vec![Coin::new(20, "uosmo")],
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "trader".to_string(),
+                amount: vec![Coin::new(1980, "uosmo")],
+            }),
+        ]
+    );
+    let data: crate::msg::SwapResponse = from_json(resp.data.unwrap()).unwrap();
+    assert_eq!(data.amount_sent_to_user, Uint128::new(1980));
+    assert_eq!(data.amount_sent_to_affiliate, Uint128::new(20));
+
+    // Exact-out swaps cannot carry an output-side fee
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SwapExactAmountOut {
+            routes: vec![SwapAmountOutRoute {
+                pool_id: 1,
+                token_in_denom: "uion".to_string(),
+            }],
+            token_in_max_amount: Uint128::new(1000),
+            token_out: Coin::new(500, "uosmo"),
+        },
+        affiliate_id: Some("usdc".to_string()),
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1000, "uion")]);
+    let err = execute(deps.as_mut(), mock_env(), info, msg).unwrap_err();
+    assert_eq!(err, ContractError::OutputFeeOnExactOut {});
+}
+
+#[test]
+fn test_migrate_from_v0_1() {
+    let mut deps = mock_dependencies();
+    cw2::set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "0.1.0").unwrap();
+    // Config layout written by 0.1.0
+    deps.as_mut().storage.set(
+        b"config",
+        br#"{"owner":"owner","affiliate_addr":"affiliate","affiliate_bps":250}"#,
+    );
+
+    migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap();
+
+    let bin = query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap();
+    let resp: crate::msg::ConfigResponse = from_json(bin).unwrap();
+    assert_eq!(resp.affiliate_bps, 250);
+    assert!(!resp.enforce_affiliate_bps);
+    assert_eq!(resp.affiliate_fee_side, FeeSide::Input);
+    let version = cw2::get_contract_version(deps.as_ref().storage).unwrap();
+    assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn test_migrate_rejects_downgrade_and_foreign_contract() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    cw2::set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "99.0.0").unwrap();
+    let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap_err();
+    assert!(matches!(err, ContractError::CannotDowngrade { .. }));
+
+    cw2::set_contract_version(deps.as_mut().storage, "crates.io:other", "0.1.0").unwrap();
+    let err = migrate(deps.as_mut(), mock_env(), MigrateMsg {}).unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::InvalidContractName {
+            name: "crates.io:other".to_string()
+        }
+    );
+}
+
+#[test]
+fn test_two_step_ownership_transfer() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let env = mock_env();
+
+    let err = execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info("owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::NoPendingOwnership {});
+
+    let msg = ExecuteMsg::TransferOwnership {
+        new_owner: "new_owner".to_string(),
+        expiry: Some(cw_utils::Expiration::AtHeight(env.block.height + 10)),
+    };
+    execute(deps.as_mut(), env.clone(), mock_info("owner", &[]), msg).unwrap();
+
+    // Owner is unchanged until the transfer is accepted
+    let bin = query(deps.as_ref(), env.clone(), QueryMsg::Ownership {}).unwrap();
+    let resp: crate::msg::OwnershipResponse = from_json(bin).unwrap();
+    assert_eq!(resp.owner.as_deref(), Some("owner"));
+    assert_eq!(resp.pending_owner.as_deref(), Some("new_owner"));
+    assert_eq!(
+        resp.pending_expiry,
+        Some(cw_utils::Expiration::AtHeight(env.block.height + 10))
+    );
+
+    let err = execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info("someone", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+
+    let mut late = env.clone();
+    late.block.height += 10;
+    let err = execute(
+        deps.as_mut(),
+        late,
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::OwnershipTransferExpired {});
+
+    execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap();
+    let bin = query(deps.as_ref(), env, QueryMsg::Ownership {}).unwrap();
+    let resp: crate::msg::OwnershipResponse = from_json(bin).unwrap();
+    assert_eq!(resp.owner.as_deref(), Some("new_owner"));
+    assert_eq!(resp.pending_owner, None);
+}
+
+#[test]
+fn test_cancel_and_renounce_ownership() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let msg = ExecuteMsg::TransferOwnership {
+        new_owner: "new_owner".to_string(),
+        expiry: None,
+    };
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::CancelOwnershipTransfer {},
+    )
+    .unwrap();
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::NoPendingOwnership {});
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::RenounceOwnership {},
+    )
+    .unwrap();
+    let bin = query(deps.as_ref(), mock_env(), QueryMsg::Ownership {}).unwrap();
+    let resp: crate::msg::OwnershipResponse = from_json(bin).unwrap();
+    assert_eq!(resp.owner, None);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+}
+
+#[test]
+fn test_pause_swaps_lets_in_flight_reply_complete() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetGuardian {
+            guardian: Some("guardian".to_string()),
+        },
+    )
+    .unwrap();
+
+    // Swap dispatched before the pause
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("trader", &[]),
+        ExecuteMsg::Pause {
+            operations: vec![PausableOperation::Swaps],
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("guardian", &[]),
+        ExecuteMsg::Pause {
+            operations: vec![PausableOperation::Swaps],
+        },
+    )
+    .unwrap();
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "1000".to_string(),
+    });
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Paused {});
+
+    // Config changes are a separate operation and stay available
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap();
+
+    let bin = query(deps.as_ref(), mock_env(), QueryMsg::PauseStatus {}).unwrap();
+    let resp: crate::msg::PauseStatusResponse = from_json(bin).unwrap();
+    assert!(resp.swaps_paused);
+    assert!(!resp.config_paused);
+    assert_eq!(resp.guardian.as_deref(), Some("guardian"));
+
+    // Only the owner can unpause
+    let unpause = ExecuteMsg::Unpause {
+        operations: vec![PausableOperation::Swaps],
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("guardian", &[]),
+        unpause.clone(),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), unpause).unwrap();
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_pause_config_changes() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::Pause {
+            operations: vec![PausableOperation::Config],
+        },
+    )
+    .unwrap();
+
+    let msg = ExecuteMsg::RegisterAffiliate {
+        id: "wallet".to_string(),
+        affiliate_addr: "partner".to_string(),
+        affiliate_bps: 30,
+        recipients: None,
+        fee_side: None,
+    };
+    let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+    assert_eq!(err, ContractError::Paused {});
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_simulate_proxy_swap() {
+    let mut deps = stargate_dependencies(vec![
+        (
+            "/osmosis.poolmanager.v1beta1.Query/EstimateSinglePoolSwapExactAmountIn",
+            to_json_binary(&EstimateSwapExactAmountInResponse {
+                token_out_amount: "1980".to_string(),
+            })
+            .unwrap(),
+        ),
+        (
+            "/osmosis.poolmanager.v1beta1.Query/EstimateSinglePoolSwapExactAmountOut",
+            to_json_binary(&EstimateSwapExactAmountOutResponse {
+                token_in_amount: "880".to_string(),
+            })
+            .unwrap(),
+        ),
+    ]);
+    mock_instantiate(&mut deps);
+
+    let simulate = |deps: &OwnedDeps<_, _, _>, swap: ProxySwap| {
+        let bin = query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::SimulateProxySwap {
+                swap,
+                gross_funds: vec![Coin::new(1025, "uion")],
+                affiliate_id: None,
+                sender: None,
+            },
+        )
+        .unwrap();
+        from_json::<crate::msg::SimulateProxySwapResponse>(bin).unwrap()
+    };
+
+    let resp = simulate(&deps, single_swap_payload(Coin::new(1000, "uion")));
+    assert_eq!(resp.fee_side, FeeSide::Input);
+    assert_eq!(resp.affiliate_fee, Coin::new(25, "uion"));
+    assert_eq!(resp.net_token_in, Coin::new(1000, "uion"));
+    assert_eq!(resp.expected_token_out, Coin::new(1980, "uosmo"));
+    assert!(resp.expected_token_in_refund.is_zero());
+
+    let resp = simulate(
+        &deps,
+        ProxySwap::SwapExactAmountOut {
+            routes: vec![SwapAmountOutRoute {
+                pool_id: 1,
+                token_in_denom: "uion".to_string(),
+            }],
+            token_in_max_amount: Uint128::new(1000),
+            token_out: Coin::new(500, "uosmo"),
+        },
+    );
+    assert_eq!(resp.expected_token_out, Coin::new(500, "uosmo"));
+    assert_eq!(resp.expected_token_in_refund, Uint128::new(120));
+
+    // Same validation as execution: an underfunded payload is rejected
+    let err = query(
+        deps.as_ref(),
+        mock_env(),
+        QueryMsg::SimulateProxySwap {
+            swap: single_swap_payload(Coin::new(2000, "uion")),
+            gross_funds: vec![Coin::new(1025, "uion")],
+            affiliate_id: None,
+            sender: None,
+        },
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("insufficient funds"));
+}
+
+#[test]
+fn test_proxy_swap_to_recipient() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SwapExactAmountOut {
+            routes: vec![SwapAmountOutRoute {
+                pool_id: 1,
+                token_in_denom: "uion".to_string(),
+            }],
+            token_in_max_amount: Uint128::new(1000),
+            token_out: Coin::new(500, "uosmo"),
+        },
+        affiliate_id: None,
+        recipient: Some("merchant".to_string()),
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert!(resp
+        .attributes
+        .iter()
+        .any(|a| a.key == "recipient" && a.value == "merchant"));
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountOutResponse {
+        token_in_amount: "880".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    // Output goes to the recipient, unused input back to the sender
+    assert_eq!(
+        resp.messages
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "merchant".to_string(),
+                amount: vec![Coin::new(500, "uosmo")],
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "trader".to_string(),
+                amount: vec![Coin::new(120, "uion")],
+            }),
+        ]
+    );
+    let data: crate::msg::SwapResponse = from_json(resp.data.unwrap()).unwrap();
+    assert_eq!(data.original_sender, "trader");
+    assert_eq!(data.recipient, "merchant");
+}
+
+#[test]
+fn test_invalid_ibc_forward_is_rejected() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let forward = |timeout: Option<u64>, memo: Option<&str>| ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: Some(IbcForward {
+            channel: "channel-0".to_string(),
+            receiver: "cosmos1receiver".to_string(),
+            timeout,
+            memo: memo.map(str::to_string),
+        }),
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    for (timeout, memo) in [
+        (Some(u64::MAX), None),
+        (Some(7 * 24 * 60 * 60 + 1), None),
+        (None, Some("{not json}")),
+        (None, Some(r#"{"a":1}junk{"b":2}"#)),
+        (None, Some(r#"{"a":1},{"b":2}"#)),
+        (None, Some(r#"["a"]"#)),
+        (None, Some(r#"{"ibc_callback":"osmo1attacker"}"#)),
+        (None, Some(r#"{"ibc\u005fcallback":"osmo1attacker"}"#)),
+    ] {
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            info.clone(),
+            forward(timeout, memo),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, ContractError::InvalidIbcForward { .. }),
+            "{timeout:?} {memo:?}: {err}"
+        );
+    }
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        forward(
+            Some(7 * 24 * 60 * 60),
+            Some(r#" {"wasm":{"msg":{"n":[1,-2,null]}}} "#),
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_ibc_forward_timeout_is_recoverable() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let env = mock_env();
+
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: Some(IbcForward {
+            channel: "channel-0".to_string(),
+            receiver: "cosmos1receiver".to_string(),
+            timeout: Some(60),
+            memo: Some(r#"{"note":"hi"}"#.to_string()),
+        }),
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "700".to_string(),
+    });
+    let resp = reply(deps.as_mut(), env.clone(), reply_msg).unwrap();
+    assert_eq!(resp.messages.len(), 1);
+    assert_eq!(resp.messages[0].id, IBC_TRANSFER_REPLY_ID_START);
+    let expected: CosmosMsg = MsgTransfer {
+        source_port: "transfer".to_string(),
+        source_channel: "channel-0".to_string(),
+        token: Some(Coin::new(700, "uosmo").into()),
+        sender: env.contract.address.to_string(),
+        receiver: "cosmos1receiver".to_string(),
+        timeout_height: None,
+        timeout_timestamp: env.block.time.plus_seconds(60).nanos(),
+        memo: format!(
+            r#"{{"ibc_callback":"{}","note":"hi"}}"#,
+            env.contract.address
+        ),
+    }
+    .into();
+    assert_eq!(resp.messages[0].msg, expected);
+
+    let reply_msg = cosmwasm_std::Reply {
+        id: IBC_TRANSFER_REPLY_ID_START,
+        ..encoded_reply(MsgTransferResponse { sequence: 7 })
+    };
+    reply(deps.as_mut(), env.clone(), reply_msg).unwrap();
+
+    // An unknown sequence is ignored; the timeout of ours credits the sender
+    let timeout = |sequence| {
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+            channel: "channel-0".to_string(),
+            sequence,
+        })
+    };
+    sudo(deps.as_mut(), env.clone(), timeout(8)).unwrap();
+    sudo(deps.as_mut(), env.clone(), timeout(7)).unwrap();
+    let recoverable: RecoverableResponse = from_json(
+        query(
+            deps.as_ref(),
+            env.clone(),
+            QueryMsg::Recoverable {
+                address: "trader".to_string(),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(recoverable.funds, vec![Coin::new(700, "uosmo")]);
+
+    let claim = ExecuteMsg::ClaimRecoverable {};
+    let resp = execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info("trader", &[]),
+        claim.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "trader".to_string(),
+            amount: vec![Coin::new(700, "uosmo")],
+        })
+    );
+    let err = execute(deps.as_mut(), env, mock_info("trader", &[]), claim).unwrap_err();
+    assert_eq!(err, ContractError::NothingToClaim {});
+}
+
+#[test]
+fn test_post_swap_action_refunds_on_failure() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let callback = Binary::from(br#"{"deposit":{}}"#.to_vec());
+    let msg = ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: Some(PostSwapAction::Wasm {
+            contract_addr: "vault".to_string(),
+            msg: callback.clone(),
+        }),
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "700".to_string(),
+    });
+    let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    assert_eq!(
+        resp.messages,
+        vec![SubMsg::reply_always(
+            WasmMsg::Execute {
+                contract_addr: "vault".to_string(),
+                msg: callback,
+                funds: vec![Coin::new(700, "uosmo")],
+            },
+            POST_SWAP_REPLY_ID_START,
+        )]
+    );
+
+    let failed = cosmwasm_std::Reply {
+        id: POST_SWAP_REPLY_ID_START,
+        result: cosmwasm_std::SubMsgResult::Err("vault is full".to_string()),
+    };
+    let resp = reply(deps.as_mut(), mock_env(), failed).unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "trader".to_string(),
+            amount: vec![Coin::new(700, "uosmo")],
+        })
+    );
+}
+
+#[test]
+fn test_ibc_hooks_swap_uses_fallback_address() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let env = mock_env();
+
+    let msg = ExecuteMsg::IbcHooksProxySwap {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        ibc_forward: IbcForward {
+            channel: "channel-0".to_string(),
+            receiver: "cosmos1receiver".to_string(),
+            timeout: None,
+            memo: None,
+        },
+        fallback_address: "fallback".to_string(),
+    };
+    // Extra denom attached by the hooks sender is refunded to the fallback address
+    let info = mock_info(
+        "hooksderived",
+        &[Coin::new(1025, "uion"), Coin::new(5, "uatom")],
+    );
+    let resp = execute(deps.as_mut(), env.clone(), info, msg).unwrap();
+    assert!(resp.messages.iter().any(|m| m.msg
+        == CosmosMsg::Bank(BankMsg::Send {
+            to_address: "fallback".to_string(),
+            amount: vec![Coin::new(5, "uatom")],
+        })));
+
+    let reply_msg = encoded_reply(MsgSwapExactAmountInResponse {
+        token_out_amount: "700".to_string(),
+    });
+    reply(deps.as_mut(), env.clone(), reply_msg).unwrap();
+    let reply_msg = cosmwasm_std::Reply {
+        id: IBC_TRANSFER_REPLY_ID_START,
+        ..encoded_reply(MsgTransferResponse { sequence: 3 })
+    };
+    reply(deps.as_mut(), env.clone(), reply_msg).unwrap();
+
+    let failed_ack = SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+        channel: "channel-0".to_string(),
+        sequence: 3,
+        ack: "error".to_string(),
+        success: false,
+    });
+    sudo(deps.as_mut(), env.clone(), failed_ack).unwrap();
+    let recoverable: RecoverableResponse = from_json(
+        query(
+            deps.as_ref(),
+            env,
+            QueryMsg::Recoverable {
+                address: "fallback".to_string(),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(recoverable.funds, vec![Coin::new(700, "uosmo")]);
+}
+
+#[test]
+fn test_concurrent_swaps_keep_separate_reply_state() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    for sender in ["alice", "bob"] {
+        let info = mock_info(sender, &[Coin::new(1025, "uion")]);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            single_swap(Coin::new(1000, "uion")),
+        )
+        .unwrap();
+    }
+
+    // Replies resolve out of order, each against its own nonce
+    for (nonce, sender, out) in [(1, "bob", "300"), (0, "alice", "700")] {
+        let reply_msg = cosmwasm_std::Reply {
+            id: SWAP_REPLY_ID_START + nonce,
+            ..encoded_reply(MsgSwapExactAmountInResponse {
+                token_out_amount: out.to_string(),
+            })
+        };
+        let resp = reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+        assert_eq!(
+            resp.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: sender.to_string(),
+                amount: vec![Coin::new(out.parse().unwrap(), "uosmo")],
+            })
+        );
+    }
+}
+
+#[test]
+fn test_batch_proxy_swap_pays_out_once() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let msg = ExecuteMsg::BatchProxySwap {
+        swaps: vec![
+            single_swap_payload(Coin::new(1000, "uion")),
+            single_swap_payload(Coin::new(2000, "uatom")),
+        ],
+        affiliate_id: None,
+    };
+    // Each leg's fee is the difference between its share of the funds and its input
+    let short = mock_info(
+        "trader",
+        &[Coin::new(1025, "uion"), Coin::new(1999, "uatom")],
+    );
+    let err = execute(deps.as_mut(), mock_env(), short, msg.clone()).unwrap_err();
+    assert_eq!(err, ContractError::InsufficientFunds {});
+
+    let info = mock_info(
+        "trader",
+        &[Coin::new(1025, "uion"), Coin::new(2050, "uatom")],
+    );
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(
+        resp.messages[..2]
+            .iter()
+            .map(|m| m.msg.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "affiliate".to_string(),
+                amount: vec![Coin::new(25, "uion")],
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "affiliate".to_string(),
+                amount: vec![Coin::new(50, "uatom")],
+            }),
+        ]
+    );
+    // Nonce 0 is reserved for the batch itself
+    let ids: Vec<u64> = resp.messages[2..].iter().map(|m| m.id).collect();
+    assert_eq!(ids, vec![SWAP_REPLY_ID_START + 1, SWAP_REPLY_ID_START + 2]);
+
+    let leg_reply = |nonce, out: &str| cosmwasm_std::Reply {
+        id: SWAP_REPLY_ID_START + nonce,
+        ..encoded_reply(MsgSwapExactAmountInResponse {
+            token_out_amount: out.to_string(),
+        })
+    };
+    let resp = reply(deps.as_mut(), mock_env(), leg_reply(2, "1400")).unwrap();
+    assert!(resp.messages.is_empty());
+    assert!(resp.data.is_none());
+
+    let resp = reply(deps.as_mut(), mock_env(), leg_reply(1, "700")).unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "trader".to_string(),
+            amount: vec![Coin::new(2100, "uosmo")],
+        })
+    );
+    let data: crate::msg::BatchSwapResponse = from_json(resp.data.unwrap()).unwrap();
+    assert_eq!(data.payout, vec![Coin::new(2100, "uosmo")]);
+    assert_eq!(data.swaps.len(), 2);
+}
+
+#[test]
+fn test_batch_enforced_fee_is_charged_per_denom() {
+    let mut deps = mock_dependencies();
+    instantiate_enforced(&mut deps, 250);
+
+    let msg = ExecuteMsg::BatchProxySwap {
+        swaps: vec![
+            single_swap_payload(Coin::new(10, "uion")),
+            single_swap_payload(Coin::new(1000, "uion")),
+        ],
+        affiliate_id: None,
+    };
+    // Charged per leg, 10 and 1000 would need 11 and 1025 (the 1-unit minimum fee), which
+    // no split of the funds yields. Charged on the total, 1035 nets exactly 1010.
+    let info = mock_info("trader", &[Coin::new(1036, "uion")]);
+    let err = execute(deps.as_mut(), mock_env(), info, msg.clone()).unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::AffiliateFeeMismatch {
+            expected: Uint128::new(1011),
+            actual: Uint128::new(1010),
+        }
+    );
+
+    let info = mock_info("trader", &[Coin::new(1035, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "affiliate".to_string(),
+            amount: vec![Coin::new(25, "uion")],
+        })
+    );
+    assert_eq!(resp.messages.len(), 3);
+
+    // The fee is reported once, on the first leg
+    let leg_reply = |nonce| cosmwasm_std::Reply {
+        id: SWAP_REPLY_ID_START + nonce,
+        ..encoded_reply(MsgSwapExactAmountInResponse {
+            token_out_amount: "500".to_string(),
+        })
+    };
+    reply(deps.as_mut(), mock_env(), leg_reply(1)).unwrap();
+    let resp = reply(deps.as_mut(), mock_env(), leg_reply(2)).unwrap();
+    let data: crate::msg::BatchSwapResponse = from_json(resp.data.unwrap()).unwrap();
+    let fees: Vec<usize> = data
+        .swaps
+        .iter()
+        .map(|s| s.affiliate_payouts.len())
+        .collect();
+    assert_eq!(fees, vec![1, 0]);
+}
+
+#[test]
+fn test_swap_history_queries() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    register(deps.as_mut(), "wallet", "wallet_fees", 100);
+
+    let swaps = [("alice", None), ("bob", Some("wallet")), ("alice", None)];
+    for (nonce, (sender, affiliate_id)) in swaps.into_iter().enumerate() {
+        let msg = ExecuteMsg::ProxySwapWithFee {
+            swap: single_swap_payload(Coin::new(1000, "uion")),
+            affiliate_id: affiliate_id.map(str::to_string),
+            recipient: None,
+            ibc_forward: None,
+            post_swap_action: None,
+            deadline: None,
+        };
+        let info = mock_info(sender, &[Coin::new(1025, "uion")]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let reply_msg = cosmwasm_std::Reply {
+            id: SWAP_REPLY_ID_START + nonce as u64,
+            ..encoded_reply(MsgSwapExactAmountInResponse {
+                token_out_amount: "700".to_string(),
+            })
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    }
+
+    let query_swaps = |msg| -> SwapsResponse {
+        from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+    let page = query_swaps(QueryMsg::SwapsBySender {
+        sender: "alice".to_string(),
+        start_after: None,
+        limit: Some(1),
+    });
+    assert_eq!(page.swaps.len(), 1);
+    assert_eq!(page.swaps[0].id, 1);
+    assert_eq!(page.swaps[0].token_in, Coin::new(1000, "uion"));
+    assert_eq!(page.swaps[0].token_out, Coin::new(700, "uosmo"));
+    assert_eq!(page.swaps[0].affiliate_fee, Coin::new(25, "uion"));
+    assert_eq!(page.swaps[0].height, mock_env().block.height);
+    let page = query_swaps(QueryMsg::SwapsBySender {
+        sender: "alice".to_string(),
+        start_after: Some(1),
+        limit: None,
+    });
+    assert_eq!(page.swaps.iter().map(|s| s.id).collect::<Vec<_>>(), vec![3]);
+
+    let page = query_swaps(QueryMsg::SwapsByAffiliate {
+        affiliate: "wallet_fees".to_string(),
+        start_after: None,
+        limit: None,
+    });
+    assert_eq!(page.swaps.len(), 1);
+    assert_eq!(page.swaps[0].sender, "bob");
+    assert_eq!(page.swaps[0].affiliate_id, Some("wallet".to_string()));
+
+    let record: SwapRecordResponse =
+        from_json(query(deps.as_ref(), mock_env(), QueryMsg::Swap { id: 2 }).unwrap()).unwrap();
+    assert_eq!(record.affiliate, "wallet_fees");
+}
+
+#[test]
+fn test_fee_and_volume_stats() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    register(deps.as_mut(), "wallet", "wallet_fees", 100);
+
+    let swaps = [("uion", None), ("uatom", None), ("uion", Some("wallet"))];
+    for (nonce, (denom, affiliate_id)) in swaps.into_iter().enumerate() {
+        let msg = ExecuteMsg::ProxySwapWithFee {
+            swap: single_swap_payload(Coin::new(1000, denom)),
+            affiliate_id: affiliate_id.map(str::to_string),
+            recipient: None,
+            ibc_forward: None,
+            post_swap_action: None,
+            deadline: None,
+        };
+        let info = mock_info("trader", &[Coin::new(1025, denom)]);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let reply_msg = cosmwasm_std::Reply {
+            id: SWAP_REPLY_ID_START + nonce as u64,
+            ..encoded_reply(MsgSwapExactAmountInResponse {
+                token_out_amount: "700".to_string(),
+            })
+        };
+        reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    }
+
+    let query_stats = |msg| -> StatsResponse {
+        from_json(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap()
+    };
+    let page = query_stats(QueryMsg::AffiliateStats {
+        affiliate: "affiliate".to_string(),
+        start_after: Some("uatom".to_string()),
+        limit: None,
+    });
+    assert_eq!(
+        page.stats,
+        vec![DenomStatsResponse {
+            denom: "uion".to_string(),
+            fees_collected: Uint128::new(25),
+            volume: Uint128::new(1000),
+        }]
+    );
+
+    let global = query_stats(QueryMsg::GlobalStats {
+        start_after: None,
+        limit: None,
+    });
+    let totals: Vec<_> = global
+        .stats
+        .iter()
+        .map(|s| (s.denom.as_str(), s.fees_collected.u128(), s.volume.u128()))
+        .collect();
+    assert_eq!(totals, vec![("uatom", 25, 1000), ("uion", 50, 2000)]);
+}
+
+#[test]
+fn test_accrued_fees_claimed_by_withdraw_address() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: Some(true),
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap();
+
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    // Only the swap itself is dispatched; the fee stays in the contract
+    assert_eq!(resp.messages.len(), 1);
+    let pending: PendingFeesResponse = from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::PendingFees {
+                affiliate: "affiliate".to_string(),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(pending.fees, vec![Coin::new(25, "uion")]);
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("affiliate", &[]),
+        ExecuteMsg::SetFeeWithdrawAddress {
+            address: Some("treasury".to_string()),
+        },
+    )
+    .unwrap();
+    let claim = ExecuteMsg::ClaimFees {
+        affiliate: Some("affiliate".to_string()),
+        denoms: None,
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("stranger", &[]),
+        claim.clone(),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("treasury", &[]),
+        claim.clone(),
+    )
+    .unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "treasury".to_string(),
+            amount: vec![Coin::new(25, "uion")],
+        })
+    );
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("affiliate", &[]),
+        claim,
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::NothingToClaim {});
+}
+
+#[test]
+fn test_convert_fees_into_settlement_denom() {
+    let mut deps = stargate_dependencies(vec![(
+        "/osmosis.twap.v1beta1.Query/ArithmeticTwapToNow",
+        to_json_binary(&ArithmeticTwapToNowResponse {
+            arithmetic_twap: "2.000000000000000000".to_string(),
+        })
+        .unwrap(),
+    )]);
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: Some(true),
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: None,
+        },
+    )
+    .unwrap();
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("trader", &[Coin::new(10250, "uion")]),
+        single_swap(Coin::new(10000, "uion")),
+    )
+    .unwrap();
+
+    let route = |denom_out: &str| ExecuteMsg::SetConversionRoute {
+        denom_in: "uion".to_string(),
+        denom_out: "uosmo".to_string(),
+        route: Some(vec![SwapAmountInRoute {
+            pool_id: 7,
+            token_out_denom: denom_out.to_string(),
+        }]),
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        route("uatom"),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::InvalidConversionRoute { .. }));
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        route("uosmo"),
+    )
+    .unwrap();
+
+    // Nothing converts until the affiliate picks a settlement denom
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("anyone", &[]),
+        ExecuteMsg::ConvertFees {
+            start_after: None,
+            limit: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::NothingToConvert {});
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("affiliate", &[]),
+        ExecuteMsg::SetSettlementDenom {
+            denom: Some("uosmo".to_string()),
+        },
+    )
+    .unwrap();
+
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("anyone", &[]),
+        ExecuteMsg::ConvertFees {
+            start_after: None,
+            limit: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 1);
+    let expected: CosmosMsg = MsgSwapExactAmountIn {
+        sender: mock_env().contract.address.to_string(),
+        routes: vec![SwapAmountInRoute {
+            pool_id: 7,
+            token_out_denom: "uosmo".to_string(),
+        }],
+        token_in: Some(Coin::new(250, "uion").into()),
+        // 1% default slippage below 250 uion at a TWAP of 2
+        token_out_min_amount: "495".to_string(),
+    }
+    .into();
+    assert_eq!(resp.messages[0].msg, expected);
+
+    let pending_fees = |deps: &OwnedDeps<_, _, _>| -> PendingFeesResponse {
+        from_json(
+            query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::PendingFees {
+                    affiliate: "affiliate".to_string(),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    };
+    assert!(pending_fees(&deps).fees.is_empty());
+
+    let reply_msg = cosmwasm_std::Reply {
+        id: resp.messages[0].id,
+        ..encoded_reply(MsgSwapExactAmountInResponse {
+            token_out_amount: "498".to_string(),
+        })
+    };
+    reply(deps.as_mut(), mock_env(), reply_msg).unwrap();
+    let pending = pending_fees(&deps);
+    assert_eq!(pending.settlement_denom, Some("uosmo".to_string()));
+    assert_eq!(pending.fees, vec![Coin::new(498, "uosmo")]);
+
+    // A full page with nothing left to convert points at the next page instead of failing
+    let convert_page = |start_after: Option<&str>| ExecuteMsg::ConvertFees {
+        start_after: start_after.map(str::to_string),
+        limit: Some(1),
+    };
+    let info = mock_info("anyone", &[]);
+    let resp = execute(deps.as_mut(), mock_env(), info.clone(), convert_page(None)).unwrap();
+    assert!(resp.messages.is_empty());
+    assert!(resp
+        .attributes
+        .iter()
+        .any(|a| a.key == "last_recipient" && a.value == "affiliate"));
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        convert_page(Some("affiliate")),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::NothingToConvert {});
+}
+
+#[test]
+fn test_split_routes_must_agree_on_output_denom() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+
+    let split = |denoms: &[&str]| ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SplitRouteSwapExactAmountIn {
+            routes: denoms
+                .iter()
+                .map(|denom| SwapAmountInSplitRoute {
+                    pools: vec![SwapAmountInRoute {
+                        pool_id: 1,
+                        token_out_denom: denom.to_string(),
+                    }],
+                    token_in_amount: "500".to_string(),
+                })
+                .collect(),
+            token_in_denom: "uion".to_string(),
+            token_out_min_amount: Uint128::new(1),
+        },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info.clone(),
+        split(&["uosmo", "uatom"]),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::InconsistentSplitRoutes {
+            side: "end".to_string(),
+            expected: "uosmo".to_string(),
+            actual: "uatom".to_string(),
+        }
+    );
+    let err = execute(deps.as_mut(), mock_env(), info.clone(), split(&[])).unwrap_err();
+    assert_eq!(err, ContractError::EmptyRoute {});
+    execute(deps.as_mut(), mock_env(), info, split(&["uosmo", "uosmo"])).unwrap();
+}
+
+#[test]
+fn test_route_lists_restrict_pools_and_denoms() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let update = ExecuteMsg::UpdateRouteLists {
+        allowed_pools: Some(vec![2]),
+        denied_pools: None,
+        allowed_denoms: None,
+        denied_denoms: Some(vec!["uion".to_string()]),
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("trader", &[]),
+        update.clone(),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Unauthorized {});
+    execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update).unwrap();
+
+    // single_swap routes through pool 1
+    let info = mock_info("trader", &[Coin::new(1025, "uatom")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info.clone(),
+        single_swap(Coin::new(1000, "uatom")),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::PoolNotAllowed { pool_id: 1 });
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateRouteLists {
+            allowed_pools: Some(vec![]),
+            denied_pools: None,
+            allowed_denoms: None,
+            denied_denoms: None,
+        },
+    )
+    .unwrap();
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("trader", &[Coin::new(1025, "uion")]),
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::DenomNotAllowed {
+            denom: "uion".to_string()
+        }
+    );
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uatom")),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_balance_delta_accounting() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::UpdateConfig {
+            strict_funds: None,
+            enforce_affiliate_bps: None,
+            accrue_fees: None,
+            conversion_max_slippage_bps: None,
+            balance_delta_accounting: Some(true),
+        },
+    )
+    .unwrap();
+    let contract = mock_env().contract.address;
+    deps.querier
+        .update_balance(&contract, vec![Coin::new(100, "uosmo")]);
+
+    // Without response data the output is taken from the balance change
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info.clone(),
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    deps.querier
+        .update_balance(&contract, vec![Coin::new(1600, "uosmo")]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        cosmwasm_std::Reply {
+            id: resp.messages.last().unwrap().id,
+            result: cosmwasm_std::SubMsgResult::Ok(cosmwasm_std::SubMsgResponse {
+                events: vec![],
+                data: None,
+            }),
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "trader".to_string(),
+            amount: vec![Coin::new(1500, "uosmo")],
+        })
+    );
+
+    // Response data that disagrees with the balance change is rejected
+    deps.querier
+        .update_balance(&contract, vec![Coin::new(100, "uosmo")]);
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    deps.querier
+        .update_balance(&contract, vec![Coin::new(1600, "uosmo")]);
+    let err = reply(
+        deps.as_mut(),
+        mock_env(),
+        cosmwasm_std::Reply {
+            id: resp.messages.last().unwrap().id,
+            ..encoded_reply(MsgSwapExactAmountInResponse {
+                token_out_amount: "2000".to_string(),
+            })
+        },
+    )
+    .unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::OutputMismatch {
+            reported: Uint128::new(2000),
+            measured: Uint128::new(1500),
+        }
+    );
+}
+
+#[test]
+fn test_proxy_swap_rejected_past_deadline() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    let env = mock_env();
+    let swap = |deadline| ExecuteMsg::ProxySwapWithFee {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: Some(deadline),
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+
+    let err = execute(
+        deps.as_mut(),
+        env.clone(),
+        info.clone(),
+        swap(cw_utils::Expiration::AtHeight(env.block.height)),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Expired {});
+    let err = execute(
+        deps.as_mut(),
+        env.clone(),
+        info.clone(),
+        swap(cw_utils::Expiration::AtTime(
+            env.block.time.minus_seconds(1),
+        )),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::Expired {});
+
+    execute(
+        deps.as_mut(),
+        env.clone(),
+        info,
+        swap(cw_utils::Expiration::AtTime(
+            env.block.time.plus_seconds(60),
+        )),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_twap_guard_rejects_low_min_output() {
+    let mut deps = stargate_dependencies(vec![(
+        "/osmosis.twap.v1beta1.Query/ArithmeticTwapToNow",
+        to_json_binary(&ArithmeticTwapToNowResponse {
+            arithmetic_twap: "2.000000000000000000".to_string(),
+        })
+        .unwrap(),
+    )]);
+    mock_instantiate(&mut deps);
+    let set_guard = |max_slippage_bps, window_seconds| ExecuteMsg::SetTwapGuard {
+        guard: Some(TwapGuard {
+            max_slippage_bps,
+            window_seconds,
+        }),
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        set_guard(500, 0),
+    )
+    .unwrap_err();
+    assert_eq!(err, ContractError::InvalidTwapWindow {});
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        set_guard(500, 3600),
+    )
+    .unwrap();
+
+    let swap = |min_out| ExecuteMsg::ProxySwapWithFee {
+        swap: ProxySwap::SwapExactAmountIn {
+            routes: vec![SwapAmountInRoute {
+                pool_id: 1,
+                token_out_denom: "uosmo".to_string(),
+            }],
+            token_in: Coin::new(1000, "uion"),
+            token_out_min_amount: Uint128::new(min_out),
+        },
+        affiliate_id: None,
+        recipient: None,
+        ibc_forward: None,
+        post_swap_action: None,
+        deadline: None,
+    };
+    let info = mock_info("trader", &[Coin::new(1025, "uion")]);
+    // 1000 uion at a TWAP of 2 uosmo each, less 5%
+    let err = execute(deps.as_mut(), mock_env(), info.clone(), swap(1)).unwrap_err();
+    assert_eq!(
+        err,
+        ContractError::MinOutputBelowTwap {
+            min: Uint128::new(1),
+            floor: Uint128::new(1900),
+        }
+    );
+    execute(deps.as_mut(), mock_env(), info, swap(1900)).unwrap();
+}
+
+#[test]
+fn test_twap_guard_overflow_is_an_error() {
+    let mut deps = stargate_dependencies(vec![(
+        "/osmosis.twap.v1beta1.Query/ArithmeticTwapToNow",
+        to_json_binary(&ArithmeticTwapToNowResponse {
+            arithmetic_twap: "100000000000000000000".to_string(),
+        })
+        .unwrap(),
+    )]);
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetTwapGuard {
+            guard: Some(TwapGuard {
+                max_slippage_bps: 500,
+                window_seconds: 3600,
+            }),
+        },
+    )
+    .unwrap();
+
+    // 10^19 at a TWAP of 10^20 does not fit in a Uint128
+    let info = mock_info("trader", &[Coin::new(10_250_000_000_000_000_000, "uion")]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        info,
+        single_swap(Coin::new(10_000_000_000_000_000_000, "uion")),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Std(_)), "{err}");
+}
+
+#[test]
+fn test_sender_fee_overrides() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    for (address, bps) in [("treasury", 0), ("market_maker", 100)] {
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("owner", &[]),
+            ExecuteMsg::SetFeeOverride {
+                address: address.to_string(),
+                bps: Some(bps),
+            },
+        )
+        .unwrap();
+    }
+
+    // Exempt: the 25 uion attached as the fee is refunded
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("treasury", &[Coin::new(1025, "uion")]),
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    assert_eq!(resp.messages.len(), 2);
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "treasury".to_string(),
+            amount: vec![Coin::new(25, "uion")],
+        })
+    );
+
+    // Discounted to 1% of the gross input
+    let resp = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("market_maker", &[Coin::new(1025, "uion")]),
+        single_swap(Coin::new(1000, "uion")),
+    )
+    .unwrap();
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "affiliate".to_string(),
+            amount: vec![Coin::new(10, "uion")],
+        })
+    );
+    assert_eq!(
+        resp.messages[1].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "market_maker".to_string(),
+            amount: vec![Coin::new(15, "uion")],
+        })
+    );
+
+    let list: FeeOverridesResponse = from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::FeeOverrides {
+                start_after: None,
+                limit: None,
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let overrides: Vec<_> = list
+        .overrides
+        .iter()
+        .map(|o| (o.address.as_str(), o.bps))
+        .collect();
+    assert_eq!(
+        overrides,
+        vec![("market_maker", Some(100)), ("treasury", Some(0))]
+    );
+}
+
+#[test]
+fn test_ibc_hooks_fee_override_ignores_fallback_address() {
+    let mut deps = mock_dependencies();
+    mock_instantiate(&mut deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("owner", &[]),
+        ExecuteMsg::SetFeeOverride {
+            address: "treasury".to_string(),
+            bps: Some(0),
+        },
+    )
+    .unwrap();
+
+    // Naming an exempt address as the fallback does not waive the fee
+    let msg = ExecuteMsg::IbcHooksProxySwap {
+        swap: single_swap_payload(Coin::new(1000, "uion")),
+        affiliate_id: None,
+        ibc_forward: IbcForward {
+            channel: "channel-0".to_string(),
+            receiver: "cosmos1receiver".to_string(),
+            timeout: None,
+            memo: None,
+        },
+        fallback_address: "treasury".to_string(),
+    };
+    let info = mock_info("hooksderived", &[Coin::new(1025, "uion")]);
+    let resp = execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+    assert_eq!(resp.messages.len(), 2);
+    assert_eq!(
+        resp.messages[0].msg,
+        CosmosMsg::Bank(BankMsg::Send {
+            to_address: "affiliate".to_string(),
+            amount: vec![Coin::new(25, "uion")],
+        })
+    );
+}
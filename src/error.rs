@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -9,12 +9,100 @@ pub enum ContractError {
     #[error("unauthorized")]
     Unauthorized {},
 
+    #[error("operation is paused")]
+    Paused {},
+
+    #[error("no pending ownership transfer")]
+    NoPendingOwnership {},
+
+    #[error("ownership transfer expired")]
+    OwnershipTransferExpired {},
+
+    #[error("swap deadline has passed")]
+    Expired {},
+
     #[error("insufficient funds sent")]
     InsufficientFunds {},
 
     #[error("invalid affiliate bps, must be between 0 and 10_000 inclusive")]
     InvalidAffiliateBps {},
 
+    #[error("invalid fee recipients, weights must be non-zero and sum to 10_000")]
+    InvalidFeeRecipients {},
+
+    #[error("affiliate not found: {id}")]
+    AffiliateNotFound { id: String },
+
+    #[error("affiliate already registered: {id}")]
+    AffiliateAlreadyExists { id: String },
+
+    #[error("unexpected funds sent: {denom}")]
+    UnexpectedFunds { denom: String },
+
+    #[error("affiliate fee mismatch: expected net input {expected}, got {actual}")]
+    AffiliateFeeMismatch { expected: Uint128, actual: Uint128 },
+
+    #[error("output-side affiliate fee is not supported for exact-amount-out swaps")]
+    OutputFeeOnExactOut {},
+
+    #[error("output after affiliate fee {actual} is below minimum {min}")]
+    MinOutputNotMet { min: Uint128, actual: Uint128 },
+
+    #[error("cannot migrate from contract {name}")]
+    InvalidContractName { name: String },
+
+    #[error("cannot migrate from version {stored} down to {current}")]
+    CannotDowngrade { stored: String, current: String },
+
+    #[error("invalid ibc forward: {reason}")]
+    InvalidIbcForward { reason: String },
+
+    #[error("ibc_forward and post_swap_action cannot be combined")]
+    ConflictingOutputActions {},
+
+    #[error("batch must contain at least one swap")]
+    EmptyBatch {},
+
+    #[error("nothing to claim")]
+    NothingToClaim {},
+
+    #[error("invalid conversion route: {reason}")]
+    InvalidConversionRoute { reason: String },
+
+    #[error("no accrued fees to convert")]
+    NothingToConvert {},
+
+    #[error("invalid slippage bps, must be between 0 and 10_000 inclusive")]
+    InvalidSlippageBps {},
+
+    #[error("swap route must contain at least one pool")]
+    EmptyRoute {},
+
+    #[error("split routes must all {side} in {expected}, found {actual}")]
+    InconsistentSplitRoutes {
+        side: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("pool {pool_id} is not allowed in swap routes")]
+    PoolNotAllowed { pool_id: u64 },
+
+    #[error("denom {denom} is not allowed in swap routes")]
+    DenomNotAllowed { denom: String },
+
+    #[error("swap output mismatch: response reported {reported}, balance changed by {measured}")]
+    OutputMismatch {
+        reported: Uint128,
+        measured: Uint128,
+    },
+
+    #[error("twap window must be at least one second")]
+    InvalidTwapWindow {},
+
+    #[error("minimum output {min} is below the TWAP-implied floor {floor}")]
+    MinOutputBelowTwap { min: Uint128, floor: Uint128 },
+
     #[error("swap failed: {reason}")]
     FailedSwap { reason: String },
 }
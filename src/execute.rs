@@ -1,230 +1,2241 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 use cosmwasm_std::{
-    coins, Addr, BankMsg, DepsMut, Env, MessageInfo, Reply, Response, SubMsg, SubMsgResponse,
-    SubMsgResult, Uint128,
+    coins, from_json, to_json_string, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, Deps,
+    DepsMut, Empty, Env, MessageInfo, Order, Reply, Response, StdResult, Storage, SubMsg,
+    SubMsgResponse, SubMsgResult, Timestamp, Uint128, WasmMsg,
 };
+use cw_storage_plus::Bound;
+use cw_utils::Expiration;
+use osmosis_std::types::ibc::applications::transfer::v1::{MsgTransfer, MsgTransferResponse};
 use osmosis_std::types::osmosis::poolmanager::v1beta1::{
-    MsgSplitRouteSwapExactAmountIn, MsgSplitRouteSwapExactAmountInResponse, MsgSwapExactAmountIn,
-    MsgSwapExactAmountInResponse,
+    MsgSplitRouteSwapExactAmountIn, MsgSplitRouteSwapExactAmountInResponse,
+    MsgSplitRouteSwapExactAmountOut, MsgSplitRouteSwapExactAmountOutResponse, MsgSwapExactAmountIn,
+    MsgSwapExactAmountInResponse, MsgSwapExactAmountOut, MsgSwapExactAmountOutResponse,
+    SwapAmountInRoute,
 };
+use osmosis_std::types::osmosis::twap::v1beta1::TwapQuerier;
+use serde::{Deserialize, Serialize};
 
 use crate::error::ContractError;
-use crate::msg::{ProxySwap, SwapResponse};
-use crate::state::{Config, PendingSwapKind, SwapReplyState, CONFIG, SWAP_REPLY_STATE};
+use crate::msg::{
+    AffiliatePayout, BatchSwapResponse, FeeRecipient, IbcForward, IbcLifecycleComplete,
+    PostSwapAction, ProxySwap, SwapResponse,
+};
+use crate::state::{
+    swap_history, AffiliateInfo, BatchState, Config, DenomStats, FeePayout, FeeShare, FeeSide,
+    IbcForwardState, InFlightTransfer, OutputFee, PausableOperation, PendingCallback,
+    PendingConversion, PendingOwnership, PendingSwapKind, SwapRecord, SwapReplyState, TwapGuard,
+    WasmCallback, ACCRUED_FEES, AFFILIATES, AFFILIATE_STATS, BATCH_STATES, CONFIG,
+    CONVERSION_ROUTES, FEE_OVERRIDES, FEE_WITHDRAW_ADDRS, GLOBAL_STATS, IBC_TRANSFERS, PAUSE_STATE,
+    PENDING_CALLBACKS, PENDING_CONVERSIONS, PENDING_IBC_TRANSFERS, PENDING_OWNERSHIP, RECOVERABLE,
+    REPLY_NONCE, ROUTE_LISTS, SETTLEMENT_DENOMS, SWAP_RECORD_COUNT, SWAP_REPLY_STATES,
+};
 
-pub const SWAP_REPLY_ID: u64 = 1u64;
+/// Reply ids are `<kind>_REPLY_ID_START + nonce`, so each in-flight swap has its own
+/// state and several swaps can be dispatched within one transaction.
+pub const REPLY_ID_RANGE: u64 = 1 << 40;
+pub const SWAP_REPLY_ID_START: u64 = REPLY_ID_RANGE;
+pub const IBC_TRANSFER_REPLY_ID_START: u64 = 2 * REPLY_ID_RANGE;
+pub const POST_SWAP_REPLY_ID_START: u64 = 3 * REPLY_ID_RANGE;
+pub const CONVERT_REPLY_ID_START: u64 = 4 * REPLY_ID_RANGE;
+pub const DEFAULT_IBC_TIMEOUT_SECONDS: u64 = 600;
+pub const MAX_IBC_TIMEOUT_SECONDS: u64 = 7 * 24 * 60 * 60;
+/// TWAP window pricing fee conversions, long enough that one tx cannot move it.
+pub const CONVERSION_TWAP_WINDOW_SECONDS: u64 = 3600;
+const DEFAULT_CONVERT_LIMIT: u32 = 10;
+const MAX_CONVERT_LIMIT: u32 = 30;
+
+/// Hands out the next nonce, wrapping within `REPLY_ID_RANGE`.
+fn next_reply_nonce(storage: &mut dyn Storage) -> StdResult<u64> {
+    let nonce = match REPLY_NONCE.may_load(storage)? {
+        Some(last) => (last + 1) % REPLY_ID_RANGE,
+        None => 0,
+    };
+    REPLY_NONCE.save(storage, &nonce)?;
+    Ok(nonce)
+}
 
 fn assert_owner(deps: &DepsMut, sender: &Addr) -> Result<(), ContractError> {
     let cfg = CONFIG.load(deps.storage)?;
-    if cfg.owner != *sender {
+    if cfg.owner.as_ref() != Some(sender) {
         return Err(ContractError::Unauthorized {});
     }
     Ok(())
 }
 
-pub fn update_affiliate(
+fn assert_not_paused(deps: &DepsMut, op: PausableOperation) -> Result<(), ContractError> {
+    let paused = PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default();
+    if paused.is_paused(op) {
+        return Err(ContractError::Paused {});
+    }
+    Ok(())
+}
+
+/// Affiliate fee charged on `gross` at `bps`. Rounds down, but charges a minimum
+/// of 1 unit when `bps > 0` and `gross` is non-zero.
+pub fn compute_affiliate_fee(gross: Uint128, bps: u16) -> Uint128 {
+    if bps == 0 || gross.is_zero() {
+        return Uint128::zero();
+    }
+    let fee = gross.multiply_ratio(bps as u128, 10_000u128);
+    if fee.is_zero() {
+        Uint128::one()
+    } else {
+        fee
+    }
+}
+
+/// Splits `fee` across `shares` by weight. Rounding dust goes to the first recipient so
+/// the payouts always add up to `fee`.
+pub fn split_fee(fee: Uint128, shares: &[FeeShare]) -> Vec<(Addr, Uint128)> {
+    let mut payouts: Vec<(Addr, Uint128)> = shares
+        .iter()
+        .map(|s| {
+            (
+                s.addr.clone(),
+                fee.multiply_ratio(s.weight as u128, 10_000u128),
+            )
+        })
+        .collect();
+    let paid = payouts
+        .iter()
+        .fold(Uint128::zero(), |acc, (_, amt)| acc + amt);
+    if let Some((_, first)) = payouts.first_mut() {
+        *first += fee - paid;
+    }
+    payouts
+}
+
+/// Smallest gross output whose remainder after the affiliate fee still meets `min_net`.
+fn gross_up_min_out(min_net: Uint128, bps: u16) -> Uint128 {
+    if min_net.is_zero() || bps >= 10_000 {
+        return min_net;
+    }
+    let mut gross = min_net.multiply_ratio(10_000u128, (10_000 - bps) as u128);
+    while gross - compute_affiliate_fee(gross, bps) < min_net {
+        gross += Uint128::one();
+    }
+    gross
+}
+
+/// Returns the affiliate cut of `gross_in` given the net amount the caller wants swapped.
+///
+/// With `enforce_affiliate_bps` set the fee is derived from the affiliate's bps and `net_in`
+/// must match the remainder exactly; otherwise the fee is the difference. Output-side
+/// affiliates take nothing from the input.
+fn resolve_affiliate_fee(
+    cfg: &Config,
+    affiliate: &AffiliateInfo,
+    gross_in: Uint128,
+    net_in: Uint128,
+) -> Result<Uint128, ContractError> {
+    if gross_in < net_in {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    if affiliate.fee_side == FeeSide::Output {
+        return Ok(Uint128::zero());
+    }
+    if !cfg.enforce_affiliate_bps {
+        return Ok(gross_in - net_in);
+    }
+    let fee = compute_affiliate_fee(gross_in, affiliate.affiliate_bps);
+    let expected = gross_in - fee;
+    if net_in != expected {
+        return Err(ContractError::AffiliateFeeMismatch {
+            expected,
+            actual: net_in,
+        });
+    }
+    Ok(fee)
+}
+
+/// Coins in `funds` not accounted for by `consumed`, merged per denom and sorted by denom.
+/// In strict mode a denom that was not consumed at all is rejected instead.
+fn leftover_funds(
+    cfg: &Config,
+    funds: &[Coin],
+    consumed: &[Coin],
+) -> Result<Vec<Coin>, ContractError> {
+    let mut remaining: BTreeMap<&str, Uint128> = BTreeMap::new();
+    for c in funds {
+        *remaining.entry(c.denom.as_str()).or_default() += c.amount;
+    }
+    for c in consumed {
+        let left = remaining
+            .get_mut(c.denom.as_str())
+            .ok_or(ContractError::InsufficientFunds {})?;
+        *left = left
+            .checked_sub(c.amount)
+            .map_err(|_| ContractError::InsufficientFunds {})?;
+    }
+    let mut leftover = vec![];
+    for (denom, amount) in remaining {
+        if cfg.strict_funds && !consumed.iter().any(|c| c.denom == denom) {
+            return Err(ContractError::UnexpectedFunds {
+                denom: denom.to_string(),
+            });
+        }
+        if !amount.is_zero() {
+            leftover.push(Coin::new(amount.u128(), denom));
+        }
+    }
+    Ok(leftover)
+}
+
+/// Returns everything in `info.funds` beyond `consumed` to the sender in `resp`.
+fn refund_leftover(
+    cfg: &Config,
+    info: &MessageInfo,
+    consumed: &[Coin],
+    resp: Response,
+) -> Result<Response, ContractError> {
+    let leftover = leftover_funds(cfg, &info.funds, consumed)?;
+    if leftover.is_empty() {
+        return Ok(resp);
+    }
+    let refunds = leftover
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(resp
+        .add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: leftover,
+        }))
+        .add_attribute("refunds", refunds))
+}
+
+pub fn update_config(
     deps: DepsMut,
     info: MessageInfo,
-    affiliate_addr: String,
-    affiliate_bps: u16,
+    strict_funds: Option<bool>,
+    enforce_affiliate_bps: Option<bool>,
+    accrue_fees: Option<bool>,
+    conversion_max_slippage_bps: Option<u16>,
+    balance_delta_accounting: Option<bool>,
 ) -> Result<Response, ContractError> {
     assert_owner(&deps, &info.sender)?;
-    if affiliate_bps > 10_000 {
-        return Err(ContractError::InvalidAffiliateBps {});
-    }
-    let addr = deps.api.addr_validate(&affiliate_addr)?;
-    CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
-        cfg.affiliate_addr = addr;
-        cfg.affiliate_bps = affiliate_bps;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        if let Some(strict) = strict_funds {
+            cfg.strict_funds = strict;
+        }
+        if let Some(enforce) = enforce_affiliate_bps {
+            cfg.enforce_affiliate_bps = enforce;
+        }
+        if let Some(accrue) = accrue_fees {
+            cfg.accrue_fees = accrue;
+        }
+        if let Some(bps) = conversion_max_slippage_bps {
+            if bps > 10_000 {
+                return Err(ContractError::InvalidSlippageBps {});
+            }
+            cfg.conversion_max_slippage_bps = bps;
+        }
+        if let Some(delta) = balance_delta_accounting {
+            cfg.balance_delta_accounting = delta;
+        }
         Ok(cfg)
     })?;
-    Ok(Response::new().add_attribute("action", "update_affiliate"))
+    let resp = Response::new().add_attribute("action", "update_config");
+    refund_leftover(&cfg, &info, &[], resp)
 }
 
-pub fn transfer_ownership(
+fn credit_accrued(
+    storage: &mut dyn Storage,
+    recipient: &Addr,
+    denom: &str,
+    amount: Uint128,
+) -> StdResult<()> {
+    ACCRUED_FEES.update(storage, (recipient, denom), |prev| -> StdResult<_> {
+        Ok(prev.unwrap_or_default() + amount)
+    })?;
+    Ok(())
+}
+
+/// Splits `fee` across `shares`, sending each share or, in accrual mode, crediting it
+/// to the recipient's claimable balance.
+fn distribute_fee(
+    storage: &mut dyn Storage,
+    cfg: &Config,
+    fee: Uint128,
+    denom: &str,
+    shares: &[FeeShare],
+) -> StdResult<(Vec<BankMsg>, Vec<FeePayout>)> {
+    let mut msgs = vec![];
+    let mut payouts = vec![];
+    for (recipient, amount) in split_fee(fee, shares) {
+        if amount.is_zero() {
+            continue;
+        }
+        if cfg.accrue_fees {
+            credit_accrued(storage, &recipient, denom, amount)?;
+        } else {
+            msgs.push(BankMsg::Send {
+                to_address: recipient.to_string(),
+                amount: coins(amount.u128(), denom),
+            });
+        }
+        payouts.push(FeePayout {
+            recipient,
+            amount: Coin::new(amount.u128(), denom),
+        });
+    }
+    Ok((msgs, payouts))
+}
+
+/// Lets `address` claim the sender's accrued fees in addition to the sender, or revokes
+/// the delegation when `None`.
+pub fn set_fee_withdraw_address(
     deps: DepsMut,
     info: MessageInfo,
-    new_owner: String,
+    address: Option<String>,
 ) -> Result<Response, ContractError> {
-    assert_owner(&deps, &info.sender)?;
-    let new_owner = deps.api.addr_validate(&new_owner)?;
-    CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
-        cfg.owner = new_owner;
-        Ok(cfg)
-    })?;
-    Ok(Response::new().add_attribute("action", "transfer_ownership"))
+    let cfg = CONFIG.load(deps.storage)?;
+    let mut resp = Response::new().add_attribute("action", "set_fee_withdraw_address");
+    match address {
+        Some(addr) => {
+            let addr = deps.api.addr_validate(&addr)?;
+            FEE_WITHDRAW_ADDRS.save(deps.storage, &info.sender, &addr)?;
+            resp = resp.add_attribute("withdraw_address", addr);
+        }
+        None => FEE_WITHDRAW_ADDRS.remove(deps.storage, &info.sender),
+    }
+    refund_leftover(&cfg, &info, &[], resp)
 }
 
-// Single proxy endpoint
-pub fn proxy_swap_with_fee(
+/// Pays out accrued fees of `affiliate` (the sender by default) to its withdraw address,
+/// or to the affiliate itself when none is set. `None` claims every denom.
+pub fn claim_fees(
     deps: DepsMut,
-    env: Env,
     info: MessageInfo,
-    swap: ProxySwap,
+    affiliate: Option<String>,
+    denoms: Option<Vec<String>>,
 ) -> Result<Response, ContractError> {
-    match swap {
-        ProxySwap::SwapExactAmountIn {
-            routes,
-            token_in,
-            token_out_min_amount,
-        } => {
-            // validate funds are at least token_in
-            let gross_in: Uint128 = info
-                .funds
-                .iter()
-                .filter(|c| c.denom == token_in.denom)
-                .fold(Uint128::zero(), |acc, c| acc + c.amount);
-            if gross_in < token_in.amount {
-                return Err(ContractError::InsufficientFunds {});
-            }
+    let cfg = CONFIG.load(deps.storage)?;
+    let affiliate = match affiliate {
+        Some(addr) => deps.api.addr_validate(&addr)?,
+        None => info.sender.clone(),
+    };
+    let withdraw_addr = FEE_WITHDRAW_ADDRS.may_load(deps.storage, &affiliate)?;
+    if info.sender != affiliate && withdraw_addr.as_ref() != Some(&info.sender) {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let denoms = match denoms {
+        Some(denoms) => denoms,
+        None => ACCRUED_FEES
+            .prefix(&affiliate)
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?,
+    };
+    let mut claimed: BTreeMap<String, Uint128> = BTreeMap::new();
+    for denom in denoms {
+        if let Some(amount) = ACCRUED_FEES.may_load(deps.storage, (&affiliate, &denom))? {
+            ACCRUED_FEES.remove(deps.storage, (&affiliate, &denom));
+            claimed.insert(denom, amount);
+        }
+    }
+    let claimed: Vec<Coin> = claimed
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(denom, amount)| Coin { denom, amount })
+        .collect();
+    if claimed.is_empty() {
+        return Err(ContractError::NothingToClaim {});
+    }
 
-            // Compute affiliate as the difference between gross funds and provided net token_in
-            let affiliate_in = gross_in.checked_sub(token_in.amount).unwrap();
+    let to = withdraw_addr.unwrap_or_else(|| affiliate.clone());
+    let resp = Response::new()
+        .add_message(BankMsg::Send {
+            to_address: to.to_string(),
+            amount: claimed,
+        })
+        .add_attribute("action", "claim_fees")
+        .add_attribute("affiliate", affiliate)
+        .add_attribute("to", to);
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-            let mut resp = Response::new().add_attribute("action", "proxy_swap_with_fee");
-            if !affiliate_in.is_zero() {
-                let cfg = CONFIG.load(deps.storage)?;
-                resp = resp.add_message(cosmwasm_std::CosmosMsg::Bank(BankMsg::Send {
-                    to_address: cfg.affiliate_addr.into_string(),
-                    amount: coins(affiliate_in.u128(), token_in.denom.clone()),
-                }));
-            }
+pub fn set_settlement_denom(
+    deps: DepsMut,
+    info: MessageInfo,
+    denom: Option<String>,
+) -> Result<Response, ContractError> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let mut resp = Response::new().add_attribute("action", "set_settlement_denom");
+    match denom {
+        Some(denom) => {
+            SETTLEMENT_DENOMS.save(deps.storage, &info.sender, &denom)?;
+            resp = resp.add_attribute("settlement_denom", denom);
+        }
+        None => SETTLEMENT_DENOMS.remove(deps.storage, &info.sender),
+    }
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-            // If nothing remains to swap, we are done
-            if token_in.amount.is_zero() {
-                return Ok(resp);
+pub fn set_conversion_route(
+    deps: DepsMut,
+    info: MessageInfo,
+    denom_in: String,
+    denom_out: String,
+    route: Option<Vec<SwapAmountInRoute>>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let invalid = |reason: &str| ContractError::InvalidConversionRoute {
+        reason: reason.to_string(),
+    };
+    match route {
+        Some(route) => {
+            if denom_in == denom_out {
+                return Err(invalid("denom_in and denom_out must differ"));
+            }
+            match route.last() {
+                None => return Err(invalid("route must not be empty")),
+                Some(hop) if hop.token_out_denom != denom_out => {
+                    return Err(invalid("route must end in denom_out"))
+                }
+                Some(_) => {}
             }
+            CONVERSION_ROUTES.save(deps.storage, (&denom_in, &denom_out), &route)?;
+        }
+        None => CONVERSION_ROUTES.remove(deps.storage, (&denom_in, &denom_out)),
+    }
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new()
+        .add_attribute("action", "set_conversion_route")
+        .add_attribute("denom_in", denom_in)
+        .add_attribute("denom_out", denom_out);
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-            let msg = MsgSwapExactAmountIn {
-                sender: env.contract.address.into_string(),
-                routes: routes.clone(),
-                token_in: Some(token_in.into()),
-                token_out_min_amount: token_out_min_amount.to_string(),
-            };
-            let out_denom = routes
-                .last()
-                .map(|r| r.token_out_denom.clone())
-                .unwrap_or_default();
+/// Swaps accrued fees into each recipient's settlement denom. Balances of the same
+/// source and settlement denom are pooled into one swap whose output is credited back
+/// pro rata. The minimum output is the route's TWAP-implied output less the configured
+/// slippage. Amounts too small to quote a non-zero minimum output are left in place.
+/// Only one page of settlement recipients is handled per call; a full page reports its
+/// last recipient so the caller can continue from there.
+pub fn convert_fees(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    assert_not_paused(&deps, PausableOperation::Swaps)?;
+    let cfg = CONFIG.load(deps.storage)?;
 
-            SWAP_REPLY_STATE.save(
-                deps.storage,
-                &SwapReplyState {
-                    original_sender: info.sender,
-                    token_out_denom: out_denom,
-                    kind: PendingSwapKind::Single,
-                },
-            )?;
+    let limit = limit
+        .unwrap_or(DEFAULT_CONVERT_LIMIT)
+        .min(MAX_CONVERT_LIMIT) as usize;
+    let start_after = start_after
+        .map(|addr| deps.api.addr_validate(&addr))
+        .transpose()?;
+    let settlements = SETTLEMENT_DENOMS
+        .range(
+            deps.storage,
+            start_after.as_ref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    let last_recipient = if settlements.len() == limit {
+        settlements.last().map(|(recipient, _)| recipient.clone())
+    } else {
+        None
+    };
+    let mut pooled: BTreeMap<(String, String), Vec<(Addr, Uint128)>> = BTreeMap::new();
+    for (recipient, denom_out) in settlements {
+        let balances = ACCRUED_FEES
+            .prefix(&recipient)
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (denom_in, amount) in balances {
+            if denom_in == denom_out
+                || amount.is_zero()
+                || !CONVERSION_ROUTES.has(deps.storage, (&denom_in, &denom_out))
+            {
+                continue;
+            }
+            pooled
+                .entry((denom_in, denom_out.clone()))
+                .or_default()
+                .push((recipient.clone(), amount));
+        }
+    }
 
-            Ok(resp.add_submessage(SubMsg::reply_on_success(msg, SWAP_REPLY_ID)))
+    let mut resp = Response::new().add_attribute("action", "convert_fees");
+    let mut converted = false;
+    for ((denom_in, denom_out), shares) in pooled {
+        let total: Uint128 = shares.iter().map(|(_, amount)| *amount).sum();
+        let routes = CONVERSION_ROUTES.load(deps.storage, (&denom_in, &denom_out))?;
+        let token_in = Coin::new(total.u128(), &denom_in);
+        // Priced off the TWAP rather than a spot estimate, which the caller could have
+        // moved earlier in the same tx
+        let expected = twap_output(
+            &TwapQuerier::new(&deps.querier),
+            env.block.time.minus_seconds(CONVERSION_TWAP_WINDOW_SECONDS),
+            total,
+            &denom_in,
+            &routes,
+        )?;
+        let min_out =
+            expected.multiply_ratio(10_000 - cfg.conversion_max_slippage_bps as u128, 10_000u128);
+        if min_out.is_zero() {
+            continue;
         }
-        ProxySwap::SplitRouteSwapExactAmountIn {
+
+        for (recipient, _) in &shares {
+            ACCRUED_FEES.remove(deps.storage, (recipient, &denom_in));
+        }
+        let nonce = next_reply_nonce(deps.storage)?;
+        PENDING_CONVERSIONS.save(
+            deps.storage,
+            nonce,
+            &PendingConversion {
+                denom_in: denom_in.clone(),
+                denom_out: denom_out.clone(),
+                shares,
+            },
+        )?;
+        let msg = MsgSwapExactAmountIn {
+            sender: env.contract.address.to_string(),
             routes,
-            token_in_denom,
-            token_out_min_amount,
-        } => {
-            // sum input
-            let mut total_in = Uint128::zero();
-            for r in &routes {
-                let amt = Uint128::from_str(&r.token_in_amount)?;
-                total_in = total_in
-                    .checked_add(amt)
-                    .map_err(|e| cosmwasm_std::StdError::generic_err(e.to_string()))?;
-            }
-            // validate funds are at least total_in
-            let gross_in: Uint128 = info
-                .funds
-                .iter()
-                .filter(|c| c.denom == token_in_denom)
-                .fold(Uint128::zero(), |acc, c| acc + c.amount);
-            if gross_in < total_in {
-                return Err(ContractError::InsufficientFunds {});
-            }
+            token_in: Some(token_in.clone().into()),
+            token_out_min_amount: min_out.to_string(),
+        };
+        resp = resp
+            .add_submessage(SubMsg::reply_always(msg, CONVERT_REPLY_ID_START + nonce))
+            .add_attribute("convert", format!("{token_in}->{denom_out}"))
+            .add_attribute("min_out", min_out);
+        converted = true;
+    }
+    // a full page may be followed by more recipients, so it is not an error on its own
+    match last_recipient {
+        Some(last) => resp = resp.add_attribute("last_recipient", last),
+        None if !converted => return Err(ContractError::NothingToConvert {}),
+        None => {}
+    }
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-            // Compute affiliate as the difference between gross funds and provided net total_in
-            let affiliate_in = gross_in.checked_sub(total_in).unwrap();
+/// Credits a conversion's output to the recipients' settlement balances, or restores
+/// their source balances when the swap failed.
+pub fn handle_convert_reply(
+    deps: DepsMut,
+    nonce: u64,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let pending = PENDING_CONVERSIONS.load(deps.storage, nonce)?;
+    PENDING_CONVERSIONS.remove(deps.storage, nonce);
 
-            let mut resp = Response::new().add_attribute("action", "proxy_split_swap_with_fee");
-            if !affiliate_in.is_zero() {
-                let cfg = CONFIG.load(deps.storage)?;
-                resp = resp.add_message(cosmwasm_std::CosmosMsg::Bank(BankMsg::Send {
-                    to_address: cfg.affiliate_addr.into_string(),
-                    amount: coins(affiliate_in.u128(), token_in_denom.clone()),
-                }));
+    let out = match msg.result {
+        SubMsgResult::Ok(SubMsgResponse { data: Some(b), .. }) => {
+            let res: MsgSwapExactAmountInResponse = b.try_into().map_err(ContractError::Std)?;
+            Uint128::from_str(&res.token_out_amount)?
+        }
+        SubMsgResult::Ok(_) => {
+            return Err(ContractError::FailedSwap {
+                reason: "missing swap response".to_string(),
+            })
+        }
+        SubMsgResult::Err(reason) => {
+            for (recipient, amount) in &pending.shares {
+                credit_accrued(deps.storage, recipient, &pending.denom_in, *amount)?;
             }
+            return Ok(Response::new()
+                .add_attribute("action", "convert_fees_failed")
+                .add_attribute("denom_in", pending.denom_in)
+                .add_attribute("reason", reason));
+        }
+    };
 
-            // If nothing remains to swap, we are done
-            if total_in.is_zero() {
-                return Ok(resp);
-            }
+    // pro rata by input, rounding dust to the first recipient
+    let total_in: Uint128 = pending.shares.iter().map(|(_, amount)| *amount).sum();
+    let mut credits: Vec<(Addr, Uint128)> = pending
+        .shares
+        .iter()
+        .map(|(recipient, amount)| (recipient.clone(), out.multiply_ratio(*amount, total_in)))
+        .collect();
+    let credited: Uint128 = credits.iter().map(|(_, amount)| *amount).sum();
+    if let Some(first) = credits.first_mut() {
+        first.1 += out - credited;
+    }
+    for (recipient, amount) in &credits {
+        credit_accrued(deps.storage, recipient, &pending.denom_out, *amount)?;
+    }
+    Ok(Response::new()
+        .add_attribute("action", "convert_fees_complete")
+        .add_attribute(
+            "token_out",
+            Coin::new(out.u128(), pending.denom_out).to_string(),
+        ))
+}
 
-            let msg = MsgSplitRouteSwapExactAmountIn {
-                sender: env.contract.address.into_string(),
-                routes: routes.clone(),
-                token_in_denom: token_in_denom,
-                token_out_min_amount: token_out_min_amount.to_string(),
-            };
-            let out_denom = routes
-                .first()
-                .and_then(|r| r.pools.last())
-                .map(|p| p.token_out_denom.clone())
-                .unwrap_or_default();
+/// Registered affiliate `id`, or the default affiliate from config when `None`.
+fn load_affiliate(
+    storage: &dyn Storage,
+    cfg: &Config,
+    id: Option<&str>,
+) -> Result<AffiliateInfo, ContractError> {
+    match id {
+        None => Ok(cfg.default_affiliate()),
+        Some(id) => AFFILIATES
+            .may_load(storage, id.to_string())?
+            .ok_or_else(|| ContractError::AffiliateNotFound { id: id.to_string() }),
+    }
+}
 
-            SWAP_REPLY_STATE.save(
-                deps.storage,
-                &SwapReplyState {
-                    original_sender: info.sender,
-                    token_out_denom: out_denom,
-                    kind: PendingSwapKind::Split,
-                },
-            )?;
+/// Builds an affiliate from the given fields; omitted `recipients` and `fee_side` are
+/// taken from `stored` when updating an existing affiliate.
+fn validate_affiliate(
+    deps: &DepsMut,
+    stored: Option<&AffiliateInfo>,
+    affiliate_addr: &str,
+    affiliate_bps: u16,
+    recipients: Option<Vec<FeeRecipient>>,
+    fee_side: Option<FeeSide>,
+) -> Result<AffiliateInfo, ContractError> {
+    if affiliate_bps > 10_000 {
+        return Err(ContractError::InvalidAffiliateBps {});
+    }
+    let recipients = match recipients {
+        Some(recipients) => validate_recipients(deps, recipients)?,
+        None => stored.map(|s| s.recipients.clone()).unwrap_or_default(),
+    };
+    Ok(AffiliateInfo {
+        affiliate_addr: deps.api.addr_validate(affiliate_addr)?,
+        affiliate_bps,
+        recipients,
+        fee_side: fee_side
+            .or_else(|| stored.map(|s| s.fee_side.clone()))
+            .unwrap_or_default(),
+    })
+}
+
+/// Weights must be non-zero and sum to 10_000; an empty list clears the split.
+fn validate_recipients(
+    deps: &DepsMut,
+    recipients: Vec<FeeRecipient>,
+) -> Result<Vec<FeeShare>, ContractError> {
+    let recipients = recipients
+        .into_iter()
+        .map(|r| {
+            if r.weight == 0 {
+                return Err(ContractError::InvalidFeeRecipients {});
+            }
+            Ok(FeeShare {
+                addr: deps.api.addr_validate(&r.addr)?,
+                weight: r.weight,
+            })
+        })
+        .collect::<Result<Vec<_>, ContractError>>()?;
+    let total_weight: u32 = recipients.iter().map(|r| r.weight as u32).sum();
+    if !recipients.is_empty() && total_weight != 10_000 {
+        return Err(ContractError::InvalidFeeRecipients {});
+    }
+    Ok(recipients)
+}
+
+pub fn register_affiliate(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: String,
+    affiliate_addr: String,
+    affiliate_bps: u16,
+    recipients: Option<Vec<FeeRecipient>>,
+    fee_side: Option<FeeSide>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let affiliate = validate_affiliate(
+        &deps,
+        None,
+        &affiliate_addr,
+        affiliate_bps,
+        recipients,
+        fee_side,
+    )?;
+    if AFFILIATES.has(deps.storage, id.clone()) {
+        return Err(ContractError::AffiliateAlreadyExists { id });
+    }
+    AFFILIATES.save(deps.storage, id.clone(), &affiliate)?;
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new()
+        .add_attribute("action", "register_affiliate")
+        .add_attribute("affiliate_id", id);
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-            Ok(resp.add_submessage(SubMsg::reply_on_success(msg, SWAP_REPLY_ID)))
+pub fn update_affiliate(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: Option<String>,
+    affiliate_addr: String,
+    affiliate_bps: u16,
+    recipients: Option<Vec<FeeRecipient>>,
+    fee_side: Option<FeeSide>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let stored = load_affiliate(deps.storage, &CONFIG.load(deps.storage)?, id.as_deref())?;
+    let affiliate = validate_affiliate(
+        &deps,
+        Some(&stored),
+        &affiliate_addr,
+        affiliate_bps,
+        recipients,
+        fee_side,
+    )?;
+    if let Some(id) = &id {
+        AFFILIATES.save(deps.storage, id.clone(), &affiliate)?;
+    }
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        if id.is_none() {
+            cfg.affiliate_addr = affiliate.affiliate_addr;
+            cfg.affiliate_bps = affiliate.affiliate_bps;
+            cfg.affiliate_recipients = affiliate.recipients;
+            cfg.affiliate_fee_side = affiliate.fee_side;
         }
+        Ok(cfg)
+    })?;
+    let resp = Response::new().add_attribute("action", "update_affiliate");
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn remove_affiliate(
+    deps: DepsMut,
+    info: MessageInfo,
+    id: String,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    if !AFFILIATES.has(deps.storage, id.clone()) {
+        return Err(ContractError::AffiliateNotFound { id });
     }
+    AFFILIATES.remove(deps.storage, id.clone());
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new()
+        .add_attribute("action", "remove_affiliate")
+        .add_attribute("affiliate_id", id);
+    refund_leftover(&cfg, &info, &[], resp)
 }
 
-pub fn handle_swap_reply(deps: DepsMut, msg: Reply) -> Result<Response, ContractError> {
-    let state = SWAP_REPLY_STATE.load(deps.storage)?;
-    SWAP_REPLY_STATE.remove(deps.storage);
+pub fn update_route_lists(
+    deps: DepsMut,
+    info: MessageInfo,
+    allowed_pools: Option<Vec<u64>>,
+    denied_pools: Option<Vec<u64>>,
+    allowed_denoms: Option<Vec<String>>,
+    denied_denoms: Option<Vec<String>>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let mut lists = ROUTE_LISTS.may_load(deps.storage)?.unwrap_or_default();
+    if let Some(pools) = allowed_pools {
+        lists.allowed_pools = pools;
+    }
+    if let Some(pools) = denied_pools {
+        lists.denied_pools = pools;
+    }
+    if let Some(denoms) = allowed_denoms {
+        lists.allowed_denoms = denoms;
+    }
+    if let Some(denoms) = denied_denoms {
+        lists.denied_denoms = denoms;
+    }
+    ROUTE_LISTS.save(deps.storage, &lists)?;
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new().add_attribute("action", "update_route_lists");
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-    let amount = if let SubMsgResult::Ok(SubMsgResponse { data: Some(b), .. }) = msg.result.clone()
-    {
-        match state.kind {
-            PendingSwapKind::Single => {
-                let res: MsgSwapExactAmountInResponse = b.try_into().map_err(ContractError::Std)?;
-                Uint128::from_str(&res.token_out_amount)?
-            }
-            PendingSwapKind::Split => {
-                let res: MsgSplitRouteSwapExactAmountInResponse =
-                    b.try_into().map_err(ContractError::Std)?;
-                Uint128::from_str(&res.token_out_amount)?
-            }
+pub fn set_twap_guard(
+    deps: DepsMut,
+    info: MessageInfo,
+    guard: Option<TwapGuard>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    if let Some(guard) = &guard {
+        if guard.max_slippage_bps > 10_000 {
+            return Err(ContractError::InvalidSlippageBps {});
         }
-    } else {
-        return Err(ContractError::FailedSwap {
-            reason: format!("{:?}", msg.result.unwrap_err()),
-        });
-    };
+        if guard.window_seconds == 0 {
+            return Err(ContractError::InvalidTwapWindow {});
+        }
+    }
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        cfg.twap_guard = guard;
+        Ok(cfg)
+    })?;
+    let resp = Response::new().add_attribute("action", "set_twap_guard");
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-    // Affiliate was taken from input; send entire output to user
-    let mut msgs: Vec<cosmwasm_std::CosmosMsg> = vec![];
-    if !amount.is_zero() {
-        msgs.push(cosmwasm_std::CosmosMsg::Bank(BankMsg::Send {
-            to_address: state.original_sender.to_string(),
-            amount: coins(amount.u128(), state.token_out_denom.clone()),
-        }));
+pub fn set_fee_override(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+    bps: Option<u16>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    assert_not_paused(&deps, PausableOperation::Config)?;
+    let address = deps.api.addr_validate(&address)?;
+    let mut resp = Response::new()
+        .add_attribute("action", "set_fee_override")
+        .add_attribute("address", &address);
+    match bps {
+        Some(bps) => {
+            if bps > 10_000 {
+                return Err(ContractError::InvalidAffiliateBps {});
+            }
+            FEE_OVERRIDES.save(deps.storage, &address, &bps)?;
+            resp = resp.add_attribute("bps", bps.to_string());
+        }
+        None => FEE_OVERRIDES.remove(deps.storage, &address),
     }
+    let cfg = CONFIG.load(deps.storage)?;
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-    let response = SwapResponse {
-        original_sender: state.original_sender.into_string(),
-        token_out_denom: state.token_out_denom,
-        amount_sent_to_user: amount,
-        amount_sent_to_affiliate: Uint128::zero(),
-    };
+pub fn set_guardian(
+    deps: DepsMut,
+    info: MessageInfo,
+    guardian: Option<String>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    let guardian = guardian.map(|g| deps.api.addr_validate(&g)).transpose()?;
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        cfg.guardian = guardian;
+        Ok(cfg)
+    })?;
+    let resp = Response::new().add_attribute("action", "set_guardian");
+    refund_leftover(&cfg, &info, &[], resp)
+}
 
-    Ok(Response::new()
-        .add_messages(msgs)
-        .set_data(cosmwasm_std::to_json_binary(&response)?)
-        .add_attribute("token_out_amount", amount))
+fn operations_attr(operations: &[PausableOperation]) -> String {
+    operations
+        .iter()
+        .map(|op| match op {
+            PausableOperation::Swaps => "swaps",
+            PausableOperation::Config => "config",
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn pause(
+    deps: DepsMut,
+    info: MessageInfo,
+    operations: Vec<PausableOperation>,
+) -> Result<Response, ContractError> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let is_owner = cfg.owner.as_ref() == Some(&info.sender);
+    let is_guardian = cfg.guardian.as_ref() == Some(&info.sender);
+    if !is_owner && !is_guardian {
+        return Err(ContractError::Unauthorized {});
+    }
+    let mut paused = PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default();
+    for op in &operations {
+        paused.set(*op, true);
+    }
+    PAUSE_STATE.save(deps.storage, &paused)?;
+    let resp = Response::new()
+        .add_attribute("action", "pause")
+        .add_attribute("operations", operations_attr(&operations));
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn unpause(
+    deps: DepsMut,
+    info: MessageInfo,
+    operations: Vec<PausableOperation>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    let mut paused = PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default();
+    for op in &operations {
+        paused.set(*op, false);
+    }
+    PAUSE_STATE.save(deps.storage, &paused)?;
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new()
+        .add_attribute("action", "unpause")
+        .add_attribute("operations", operations_attr(&operations));
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn transfer_ownership(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    new_owner: String,
+    expiry: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    let new_owner = deps.api.addr_validate(&new_owner)?;
+    if expiry.map_or(false, |e| e.is_expired(&env.block)) {
+        return Err(ContractError::OwnershipTransferExpired {});
+    }
+    PENDING_OWNERSHIP.save(
+        deps.storage,
+        &PendingOwnership {
+            new_owner: new_owner.clone(),
+            expiry,
+        },
+    )?;
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new()
+        .add_attribute("action", "transfer_ownership")
+        .add_attribute("pending_owner", new_owner);
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn accept_ownership(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let pending = PENDING_OWNERSHIP
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingOwnership {})?;
+    if pending.new_owner != info.sender {
+        return Err(ContractError::Unauthorized {});
+    }
+    if pending.expiry.map_or(false, |e| e.is_expired(&env.block)) {
+        return Err(ContractError::OwnershipTransferExpired {});
+    }
+    PENDING_OWNERSHIP.remove(deps.storage);
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        cfg.owner = Some(pending.new_owner);
+        Ok(cfg)
+    })?;
+    let resp = Response::new()
+        .add_attribute("action", "accept_ownership")
+        .add_attribute("owner", info.sender.as_str());
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn cancel_ownership_transfer(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    if !PENDING_OWNERSHIP.exists(deps.storage) {
+        return Err(ContractError::NoPendingOwnership {});
+    }
+    PENDING_OWNERSHIP.remove(deps.storage);
+    let cfg = CONFIG.load(deps.storage)?;
+    let resp = Response::new().add_attribute("action", "cancel_ownership_transfer");
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+pub fn renounce_ownership(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    assert_owner(&deps, &info.sender)?;
+    PENDING_OWNERSHIP.remove(deps.storage);
+    let cfg = CONFIG.update(deps.storage, |mut cfg| -> Result<Config, ContractError> {
+        cfg.owner = None;
+        Ok(cfg)
+    })?;
+    let resp = Response::new().add_attribute("action", "renounce_ownership");
+    refund_leftover(&cfg, &info, &[], resp)
+}
+
+/// Poolmanager message for a proxied swap, plus what the reply needs to settle it.
+pub(crate) struct PreparedSwap {
+    pub msg: CosmosMsg,
+    pub action: &'static str,
+    pub token_in_denom: String,
+    /// Net input dispatched to poolmanager (the maximum input for exact-out swaps).
+    pub token_in_amount: Uint128,
+    pub token_out_denom: String,
+    /// Minimum the user asked for; the exact output for exact-out swaps.
+    pub token_out_min_amount: Uint128,
+    pub kind: PendingSwapKind,
+}
+
+/// A proxied swap with its affiliate fee resolved against the attached funds.
+pub(crate) struct SwapQuote {
+    pub prepared: PreparedSwap,
+    pub affiliate: AffiliateInfo,
+    /// Bps to take from the output, for `FeeSide::Output` affiliates.
+    pub output_fee_bps: Option<u16>,
+    /// Affiliate cut of the input denom.
+    pub affiliate_in: Uint128,
+}
+
+/// Charges `sender`'s fee override, if any, instead of the affiliate's bps.
+fn apply_fee_override(
+    storage: &dyn Storage,
+    affiliate: &mut AffiliateInfo,
+    sender: Option<&Addr>,
+) -> StdResult<Option<u16>> {
+    let fee_override = match sender {
+        Some(sender) => FEE_OVERRIDES.may_load(storage, sender)?,
+        None => None,
+    };
+    if let Some(bps) = fee_override {
+        affiliate.affiliate_bps = bps;
+    }
+    Ok(fee_override)
+}
+
+/// Resolves the affiliate and its fee for `swap` exactly as `proxy_swap_with_fee` charges it.
+pub(crate) fn quote_swap(
+    storage: &dyn Storage,
+    contract: &Addr,
+    funds: &[Coin],
+    swap: ProxySwap,
+    affiliate_id: Option<&str>,
+    sender: Option<&Addr>,
+) -> Result<SwapQuote, ContractError> {
+    let cfg = CONFIG.load(storage)?;
+    let mut affiliate = load_affiliate(storage, &cfg, affiliate_id)?;
+    let fee_override = apply_fee_override(storage, &mut affiliate, sender)?;
+    let output_fee_bps = match affiliate.fee_side {
+        FeeSide::Input => None,
+        FeeSide::Output => Some(affiliate.affiliate_bps),
+    };
+    check_route_lists(storage, &swap)?;
+    let prepared = prepare_swap(contract, swap, output_fee_bps)?;
+
+    // gross funds of the input denom, including the affiliate cut
+    let gross_in: Uint128 = funds
+        .iter()
+        .filter(|c| c.denom == prepared.token_in_denom)
+        .fold(Uint128::zero(), |acc, c| acc + c.amount);
+    let mut affiliate_in =
+        resolve_affiliate_fee(&cfg, &affiliate, gross_in, prepared.token_in_amount)?;
+    if let Some(bps) = fee_override {
+        // Whatever was attached, the sender pays no more than its override; the rest is refunded
+        affiliate_in = affiliate_in.min(compute_affiliate_fee(gross_in, bps));
+    }
+
+    Ok(SwapQuote {
+        prepared,
+        affiliate,
+        output_fee_bps,
+        affiliate_in,
+    })
+}
+
+fn sum_amounts<'a>(amounts: impl Iterator<Item = &'a String>) -> Result<Uint128, ContractError> {
+    let mut total = Uint128::zero();
+    for amt in amounts {
+        total = total
+            .checked_add(Uint128::from_str(amt)?)
+            .map_err(|e| cosmwasm_std::StdError::generic_err(e.to_string()))?;
+    }
+    Ok(total)
+}
+
+/// Pools and denoms touched by every hop of `swap`.
+fn swap_hops(swap: &ProxySwap) -> (Vec<u64>, Vec<&str>) {
+    let mut pools = vec![];
+    let mut denoms = vec![];
+    match swap {
+        ProxySwap::SwapExactAmountIn {
+            routes, token_in, ..
+        } => {
+            denoms.push(token_in.denom.as_str());
+            for hop in routes {
+                pools.push(hop.pool_id);
+                denoms.push(&hop.token_out_denom);
+            }
+        }
+        ProxySwap::SplitRouteSwapExactAmountIn {
+            routes,
+            token_in_denom,
+            ..
+        } => {
+            denoms.push(token_in_denom);
+            for hop in routes.iter().flat_map(|r| &r.pools) {
+                pools.push(hop.pool_id);
+                denoms.push(&hop.token_out_denom);
+            }
+        }
+        ProxySwap::SwapExactAmountOut {
+            routes, token_out, ..
+        } => {
+            denoms.push(token_out.denom.as_str());
+            for hop in routes {
+                pools.push(hop.pool_id);
+                denoms.push(&hop.token_in_denom);
+            }
+        }
+        ProxySwap::SplitRouteSwapExactAmountOut {
+            routes,
+            token_out_denom,
+            ..
+        } => {
+            denoms.push(token_out_denom);
+            for hop in routes.iter().flat_map(|r| &r.pools) {
+                pools.push(hop.pool_id);
+                denoms.push(&hop.token_in_denom);
+            }
+        }
+    }
+    (pools, denoms)
+}
+
+/// Rejects swaps routed through a pool or denom excluded by the owner's route lists.
+fn check_route_lists(storage: &dyn Storage, swap: &ProxySwap) -> Result<(), ContractError> {
+    let Some(lists) = ROUTE_LISTS.may_load(storage)? else {
+        return Ok(());
+    };
+    let (pools, denoms) = swap_hops(swap);
+    if let Some(pool_id) = pools.into_iter().find(|p| !lists.pool_allowed(*p)) {
+        return Err(ContractError::PoolNotAllowed { pool_id });
+    }
+    if let Some(denom) = denoms.into_iter().find(|d| !lists.denom_allowed(d)) {
+        return Err(ContractError::DenomNotAllowed {
+            denom: denom.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Output of `amount` of `denom_in` through `hops` at each pool's arithmetic TWAP since `start`.
+fn twap_output(
+    querier: &TwapQuerier<Empty>,
+    start: Timestamp,
+    amount: Uint128,
+    denom_in: &str,
+    hops: &[SwapAmountInRoute],
+) -> Result<Uint128, ContractError> {
+    let mut amount = amount;
+    let mut denom = denom_in.to_string();
+    for hop in hops {
+        // price of the base asset in units of the quote asset
+        let res = querier.arithmetic_twap_to_now(
+            hop.pool_id,
+            denom,
+            hop.token_out_denom.clone(),
+            Some(osmosis_std::shim::Timestamp {
+                seconds: start.seconds() as i64,
+                nanos: start.subsec_nanos() as i32,
+            }),
+        )?;
+        amount = amount
+            .checked_mul_floor(Decimal::from_str(&res.arithmetic_twap)?)
+            .map_err(|e| cosmwasm_std::StdError::generic_err(e.to_string()))?;
+        denom = hop.token_out_denom.clone();
+    }
+    Ok(amount)
+}
+
+/// Rejects exact-in swaps whose minimum output is below the TWAP-implied output less the
+/// guard's slippage. With an output-side fee the floor is lowered by that fee, since
+/// `token_out_min_amount` is what the user keeps after it.
+fn check_twap_guard(
+    deps: Deps,
+    env: &Env,
+    cfg: &Config,
+    swap: &ProxySwap,
+    output_fee_bps: Option<u16>,
+) -> Result<(), ContractError> {
+    let Some(guard) = &cfg.twap_guard else {
+        return Ok(());
+    };
+    let querier = TwapQuerier::new(&deps.querier);
+    let start = env.block.time.minus_seconds(guard.window_seconds);
+    let (implied, min_out) = match swap {
+        ProxySwap::SwapExactAmountIn {
+            routes,
+            token_in,
+            token_out_min_amount,
+        } => (
+            twap_output(&querier, start, token_in.amount, &token_in.denom, routes)?,
+            *token_out_min_amount,
+        ),
+        ProxySwap::SplitRouteSwapExactAmountIn {
+            routes,
+            token_in_denom,
+            token_out_min_amount,
+        } => {
+            let mut implied = Uint128::zero();
+            for route in routes {
+                let amount = Uint128::from_str(&route.token_in_amount)?;
+                implied += twap_output(&querier, start, amount, token_in_denom, &route.pools)?;
+            }
+            (implied, *token_out_min_amount)
+        }
+        // The output of exact-out swaps is fixed by the caller
+        _ => return Ok(()),
+    };
+    let mut floor = implied.multiply_ratio(10_000 - guard.max_slippage_bps as u128, 10_000u128);
+    if let Some(bps) = output_fee_bps {
+        floor = floor.saturating_sub(compute_affiliate_fee(floor, bps));
+    }
+    if min_out < floor {
+        return Err(ContractError::MinOutputBelowTwap {
+            min: min_out,
+            floor,
+        });
+    }
+    Ok(())
+}
+
+/// Rejects empty routes and split routes that disagree on the input or output denom.
+fn validate_routes(swap: &ProxySwap) -> Result<(), ContractError> {
+    let consistent = |side: &str, denoms: Vec<&String>| {
+        let expected = denoms[0];
+        match denoms.iter().find(|d| **d != expected) {
+            Some(actual) => Err(ContractError::InconsistentSplitRoutes {
+                side: side.to_string(),
+                expected: expected.clone(),
+                actual: actual.to_string(),
+            }),
+            None => Ok(()),
+        }
+    };
+    match swap {
+        ProxySwap::SwapExactAmountIn { routes, .. } if routes.is_empty() => {
+            Err(ContractError::EmptyRoute {})
+        }
+        ProxySwap::SwapExactAmountOut { routes, .. } if routes.is_empty() => {
+            Err(ContractError::EmptyRoute {})
+        }
+        ProxySwap::SplitRouteSwapExactAmountIn { routes, .. } => {
+            let outs = routes
+                .iter()
+                .map(|r| r.pools.last().map(|p| &p.token_out_denom))
+                .collect::<Option<Vec<_>>>()
+                .filter(|outs| !outs.is_empty())
+                .ok_or(ContractError::EmptyRoute {})?;
+            consistent("end", outs)
+        }
+        ProxySwap::SplitRouteSwapExactAmountOut { routes, .. } => {
+            let ins = routes
+                .iter()
+                .map(|r| r.pools.first().map(|p| &p.token_in_denom))
+                .collect::<Option<Vec<_>>>()
+                .filter(|ins| !ins.is_empty())
+                .ok_or(ContractError::EmptyRoute {})?;
+            consistent("start", ins)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Builds the poolmanager message with `sender` overwritten to the contract address.
+///
+/// With `output_fee_bps` set, the minimum output sent to poolmanager is raised so the
+/// user's share after the output fee still meets their `token_out_min_amount`.
+fn prepare_swap(
+    contract: &Addr,
+    swap: ProxySwap,
+    output_fee_bps: Option<u16>,
+) -> Result<PreparedSwap, ContractError> {
+    let sender = contract.to_string();
+    let pool_min_out = |min: Uint128| match output_fee_bps {
+        Some(bps) => gross_up_min_out(min, bps),
+        None => min,
+    };
+    if output_fee_bps.is_some()
+        && matches!(
+            swap,
+            ProxySwap::SwapExactAmountOut { .. } | ProxySwap::SplitRouteSwapExactAmountOut { .. }
+        )
+    {
+        return Err(ContractError::OutputFeeOnExactOut {});
+    }
+    validate_routes(&swap)?;
+    let prepared = match swap {
+        ProxySwap::SwapExactAmountIn {
+            routes,
+            token_in,
+            token_out_min_amount,
+        } => {
+            let token_out_denom = routes
+                .last()
+                .map(|r| r.token_out_denom.clone())
+                .unwrap_or_default();
+            PreparedSwap {
+                action: "proxy_swap_with_fee",
+                token_in_denom: token_in.denom.clone(),
+                token_in_amount: token_in.amount,
+                token_out_denom,
+                token_out_min_amount,
+                kind: PendingSwapKind::Single,
+                msg: MsgSwapExactAmountIn {
+                    sender,
+                    routes,
+                    token_in: Some(token_in.into()),
+                    token_out_min_amount: pool_min_out(token_out_min_amount).to_string(),
+                }
+                .into(),
+            }
+        }
+        ProxySwap::SplitRouteSwapExactAmountIn {
+            routes,
+            token_in_denom,
+            token_out_min_amount,
+        } => {
+            let total_in = sum_amounts(routes.iter().map(|r| &r.token_in_amount))?;
+            let token_out_denom = routes
+                .first()
+                .and_then(|r| r.pools.last())
+                .map(|p| p.token_out_denom.clone())
+                .unwrap_or_default();
+            PreparedSwap {
+                action: "proxy_split_swap_with_fee",
+                token_in_denom: token_in_denom.clone(),
+                token_in_amount: total_in,
+                token_out_denom,
+                token_out_min_amount,
+                kind: PendingSwapKind::Split,
+                msg: MsgSplitRouteSwapExactAmountIn {
+                    sender,
+                    routes,
+                    token_in_denom,
+                    token_out_min_amount: pool_min_out(token_out_min_amount).to_string(),
+                }
+                .into(),
+            }
+        }
+        ProxySwap::SwapExactAmountOut {
+            routes,
+            token_in_max_amount,
+            token_out,
+        } => {
+            let token_in_denom = routes
+                .first()
+                .map(|r| r.token_in_denom.clone())
+                .unwrap_or_default();
+            PreparedSwap {
+                action: "proxy_swap_exact_out_with_fee",
+                token_in_denom,
+                token_in_amount: token_in_max_amount,
+                token_out_denom: token_out.denom.clone(),
+                token_out_min_amount: token_out.amount,
+                kind: PendingSwapKind::ExactOut {
+                    token_out_amount: token_out.amount,
+                },
+                msg: MsgSwapExactAmountOut {
+                    sender,
+                    routes,
+                    token_in_max_amount: token_in_max_amount.to_string(),
+                    token_out: Some(token_out.into()),
+                }
+                .into(),
+            }
+        }
+        ProxySwap::SplitRouteSwapExactAmountOut {
+            routes,
+            token_out_denom,
+            token_in_max_amount,
+        } => {
+            let total_out = sum_amounts(routes.iter().map(|r| &r.token_out_amount))?;
+            let token_in_denom = routes
+                .first()
+                .and_then(|r| r.pools.first())
+                .map(|p| p.token_in_denom.clone())
+                .unwrap_or_default();
+            PreparedSwap {
+                action: "proxy_split_swap_exact_out_with_fee",
+                token_in_denom,
+                token_in_amount: token_in_max_amount,
+                token_out_denom: token_out_denom.clone(),
+                token_out_min_amount: total_out,
+                kind: PendingSwapKind::SplitExactOut {
+                    token_out_amount: total_out,
+                },
+                msg: MsgSplitRouteSwapExactAmountOut {
+                    sender,
+                    routes,
+                    token_out_denom,
+                    token_in_max_amount: token_in_max_amount.to_string(),
+                }
+                .into(),
+            }
+        }
+    };
+    Ok(prepared)
+}
+
+/// Optional parameters of `ExecuteMsg::ProxySwapWithFee` besides the swap itself.
+#[derive(Default)]
+pub struct SwapOptions {
+    pub affiliate_id: Option<String>,
+    pub recipient: Option<String>,
+    pub ibc_forward: Option<IbcForward>,
+    pub post_swap_action: Option<PostSwapAction>,
+    pub deadline: Option<Expiration>,
+    /// Address whose fee override applies; the message sender when `None`.
+    pub fee_payer: Option<Addr>,
+}
+
+/// A JSON value as found in a forward memo. Floats are not supported by the contract's
+/// JSON codec, so memos containing them are rejected.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum MemoValue {
+    Null(()),
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    String(String),
+    Array(Vec<MemoValue>),
+    Object(BTreeMap<String, MemoValue>),
+}
+
+/// Adds the contract's `ibc_callback` entry to a caller-supplied JSON object memo.
+fn ibc_callback_memo(contract: &Addr, memo: Option<String>) -> Result<String, ContractError> {
+    let mut object = match memo.as_deref().map(str::trim) {
+        None | Some("") => BTreeMap::new(),
+        Some(memo) => from_json::<BTreeMap<String, MemoValue>>(memo).map_err(|_| {
+            ContractError::InvalidIbcForward {
+                reason: "memo must be a JSON object".to_string(),
+            }
+        })?,
+    };
+    if object.contains_key("ibc_callback") {
+        return Err(ContractError::InvalidIbcForward {
+            reason: "memo must not set ibc_callback".to_string(),
+        });
+    }
+    object.insert(
+        "ibc_callback".to_string(),
+        MemoValue::String(contract.to_string()),
+    );
+    Ok(to_json_string(&object)?)
+}
+
+fn resolve_ibc_forward(env: &Env, forward: IbcForward) -> Result<IbcForwardState, ContractError> {
+    if forward.channel.is_empty() || forward.receiver.is_empty() {
+        return Err(ContractError::InvalidIbcForward {
+            reason: "channel and receiver are required".to_string(),
+        });
+    }
+    let timeout = forward.timeout.unwrap_or(DEFAULT_IBC_TIMEOUT_SECONDS);
+    if timeout > MAX_IBC_TIMEOUT_SECONDS {
+        return Err(ContractError::InvalidIbcForward {
+            reason: format!("timeout must be at most {MAX_IBC_TIMEOUT_SECONDS} seconds"),
+        });
+    }
+    let timeout_timestamp = timeout
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| env.block.time.nanos().checked_add(nanos))
+        .ok_or_else(|| ContractError::InvalidIbcForward {
+            reason: "timeout overflows the block time".to_string(),
+        })?;
+    Ok(IbcForwardState {
+        channel: forward.channel,
+        receiver: forward.receiver,
+        timeout_timestamp,
+        memo: ibc_callback_memo(&env.contract.address, forward.memo)?,
+    })
+}
+
+/// Dispatches several swaps at once; their outputs are paid out together once all complete.
+///
+/// The fee of each input denom is resolved once, exactly as for a single swap, from the
+/// attached funds of that denom and the sum of its legs' inputs. It is charged with the
+/// denom's first leg and reported on its first non-empty one.
+pub fn batch_proxy_swap(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    swaps: Vec<ProxySwap>,
+    affiliate_id: Option<String>,
+) -> Result<Response, ContractError> {
+    assert_not_paused(&deps, PausableOperation::Swaps)?;
+    if swaps.is_empty() {
+        return Err(ContractError::EmptyBatch {});
+    }
+    let cfg = CONFIG.load(deps.storage)?;
+    let mut affiliate = load_affiliate(deps.storage, &cfg, affiliate_id.as_deref())?;
+    let fee_override = apply_fee_override(deps.storage, &mut affiliate, Some(&info.sender))?;
+    let output_fee_bps = match affiliate.fee_side {
+        FeeSide::Input => None,
+        FeeSide::Output => Some(affiliate.affiliate_bps),
+    };
+
+    let mut prepared_legs = vec![];
+    // (attached, sum of leg inputs) per input denom
+    let mut totals: BTreeMap<String, (Uint128, Uint128)> = BTreeMap::new();
+    for swap in swaps {
+        check_route_lists(deps.storage, &swap)?;
+        check_twap_guard(deps.as_ref(), &env, &cfg, &swap, output_fee_bps)?;
+        let prepared = prepare_swap(&env.contract.address, swap, output_fee_bps)?;
+        let (_, net) = totals
+            .entry(prepared.token_in_denom.clone())
+            .or_insert_with(|| {
+                let attached = info
+                    .funds
+                    .iter()
+                    .filter(|c| c.denom == prepared.token_in_denom)
+                    .fold(Uint128::zero(), |acc, c| acc + c.amount);
+                (attached, Uint128::zero())
+            });
+        *net += prepared.token_in_amount;
+        prepared_legs.push(prepared);
+    }
+
+    let mut resp = Response::new().add_attribute("action", "batch_proxy_swap");
+    if let Some(id) = &affiliate_id {
+        resp = resp.add_attribute("affiliate_id", id);
+    }
+    let mut consumed = vec![];
+    let mut unreported: BTreeMap<String, Vec<FeePayout>> = BTreeMap::new();
+    let mut legs = vec![];
+    for prepared in prepared_legs {
+        let denom = prepared.token_in_denom.clone();
+        if let Some((gross_in, net_in)) = totals.remove(&denom) {
+            let mut affiliate_in = resolve_affiliate_fee(&cfg, &affiliate, gross_in, net_in)?;
+            if let Some(bps) = fee_override {
+                affiliate_in = affiliate_in.min(compute_affiliate_fee(gross_in, bps));
+            }
+            let (fee_msgs, affiliate_payouts) = distribute_fee(
+                deps.storage,
+                &cfg,
+                affiliate_in,
+                &denom,
+                &affiliate.shares(),
+            )?;
+            resp = resp.add_messages(fee_msgs);
+            add_stats(
+                deps.storage,
+                &affiliate.affiliate_addr,
+                &denom,
+                affiliate_in,
+                Uint128::zero(),
+            )?;
+            consumed.push(Coin::new((affiliate_in + net_in).u128(), denom.clone()));
+            unreported.insert(denom.clone(), affiliate_payouts);
+        }
+        if !prepared.token_in_amount.is_zero() {
+            let affiliate_payouts = unreported.remove(&denom).unwrap_or_default();
+            legs.push((prepared, affiliate_payouts));
+        }
+    }
+    resp = refund_leftover(&cfg, &info, &consumed, resp)?;
+    if legs.is_empty() {
+        return Ok(resp);
+    }
+
+    let batch_id = next_reply_nonce(deps.storage)?;
+    BATCH_STATES.save(
+        deps.storage,
+        batch_id,
+        &BatchState {
+            recipient: info.sender.clone(),
+            pending: legs.len() as u32,
+            payout: BTreeMap::new(),
+            legs: vec![],
+        },
+    )?;
+    for (prepared, affiliate_payouts) in legs {
+        let token_out_balance_before = if cfg.balance_delta_accounting {
+            Some(balance_after_sends(
+                &deps,
+                &env.contract.address,
+                &prepared.token_out_denom,
+                &resp,
+            )?)
+        } else {
+            None
+        };
+        let nonce = next_reply_nonce(deps.storage)?;
+        SWAP_REPLY_STATES.save(
+            deps.storage,
+            nonce,
+            &SwapReplyState {
+                original_sender: info.sender.clone(),
+                affiliate_addr: affiliate.affiliate_addr.clone(),
+                affiliate_id: affiliate_id.clone(),
+                recipient: info.sender.clone(),
+                token_in_denom: prepared.token_in_denom,
+                token_in_amount: prepared.token_in_amount,
+                token_out_denom: prepared.token_out_denom,
+                kind: prepared.kind,
+                affiliate_payouts,
+                output_fee: output_fee_bps.map(|bps| OutputFee {
+                    affiliate_bps: bps,
+                    recipients: affiliate.shares(),
+                    token_out_min_amount: prepared.token_out_min_amount,
+                }),
+                ibc_forward: None,
+                post_swap_action: None,
+                batch: Some(batch_id),
+                token_out_balance_before,
+            },
+        )?;
+        resp = resp.add_submessage(SubMsg::reply_on_success(
+            prepared.msg,
+            SWAP_REPLY_ID_START + nonce,
+        ));
+    }
+    Ok(resp.add_attribute("batch_id", batch_id.to_string()))
+}
+
+// Single proxy endpoint
+pub fn proxy_swap_with_fee(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    swap: ProxySwap,
+    options: SwapOptions,
+) -> Result<Response, ContractError> {
+    assert_not_paused(&deps, PausableOperation::Swaps)?;
+    let SwapOptions {
+        affiliate_id,
+        recipient,
+        ibc_forward,
+        post_swap_action,
+        deadline,
+        fee_payer,
+    } = options;
+    if let Some(deadline) = deadline {
+        if deadline.is_expired(&env.block) {
+            return Err(ContractError::Expired {});
+        }
+    }
+    if ibc_forward.is_some() && post_swap_action.is_some() {
+        return Err(ContractError::ConflictingOutputActions {});
+    }
+    let ibc_forward = ibc_forward
+        .map(|forward| resolve_ibc_forward(&env, forward))
+        .transpose()?;
+    let post_swap_action = match post_swap_action {
+        Some(PostSwapAction::Wasm { contract_addr, msg }) => Some(WasmCallback {
+            contract_addr: deps.api.addr_validate(&contract_addr)?,
+            msg,
+        }),
+        None => None,
+    };
+    let recipient = match recipient {
+        Some(addr) => deps.api.addr_validate(&addr)?,
+        None => info.sender.clone(),
+    };
+    let cfg = CONFIG.load(deps.storage)?;
+    let SwapQuote {
+        prepared,
+        affiliate,
+        output_fee_bps,
+        affiliate_in,
+    } = quote_swap(
+        deps.storage,
+        &env.contract.address,
+        &info.funds,
+        swap.clone(),
+        affiliate_id.as_deref(),
+        Some(fee_payer.as_ref().unwrap_or(&info.sender)),
+    )?;
+    check_twap_guard(deps.as_ref(), &env, &cfg, &swap, output_fee_bps)?;
+
+    let mut resp = Response::new().add_attribute("action", prepared.action);
+    if let Some(id) = &affiliate_id {
+        resp = resp.add_attribute("affiliate_id", id);
+    }
+    if recipient != info.sender {
+        resp = resp.add_attribute("recipient", &recipient);
+    }
+    let (fee_msgs, affiliate_payouts) = distribute_fee(
+        deps.storage,
+        &cfg,
+        affiliate_in,
+        &prepared.token_in_denom,
+        &affiliate.shares(),
+    )?;
+    resp = resp.add_messages(fee_msgs);
+    add_stats(
+        deps.storage,
+        &affiliate.affiliate_addr,
+        &prepared.token_in_denom,
+        affiliate_in,
+        Uint128::zero(),
+    )?;
+    let consumed = coins(
+        (affiliate_in + prepared.token_in_amount).u128(),
+        prepared.token_in_denom.clone(),
+    );
+    resp = refund_leftover(&cfg, &info, &consumed, resp)?;
+
+    // If nothing remains to swap, we are done
+    if prepared.token_in_amount.is_zero() {
+        return Ok(resp);
+    }
+
+    let token_out_balance_before = if cfg.balance_delta_accounting {
+        Some(balance_after_sends(
+            &deps,
+            &env.contract.address,
+            &prepared.token_out_denom,
+            &resp,
+        )?)
+    } else {
+        None
+    };
+    let nonce = next_reply_nonce(deps.storage)?;
+    SWAP_REPLY_STATES.save(
+        deps.storage,
+        nonce,
+        &SwapReplyState {
+            original_sender: info.sender,
+            affiliate_addr: affiliate.affiliate_addr.clone(),
+            affiliate_id,
+            recipient,
+            token_in_denom: prepared.token_in_denom,
+            token_in_amount: prepared.token_in_amount,
+            token_out_denom: prepared.token_out_denom,
+            kind: prepared.kind,
+            affiliate_payouts,
+            output_fee: output_fee_bps.map(|bps| OutputFee {
+                affiliate_bps: bps,
+                recipients: affiliate.shares(),
+                token_out_min_amount: prepared.token_out_min_amount,
+            }),
+            ibc_forward,
+            post_swap_action,
+            batch: None,
+            token_out_balance_before,
+        },
+    )?;
+
+    Ok(resp.add_submessage(SubMsg::reply_on_success(
+        prepared.msg,
+        SWAP_REPLY_ID_START + nonce,
+    )))
+}
+
+pub fn handle_swap_reply(
+    deps: DepsMut,
+    env: Env,
+    nonce: u64,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let state = SWAP_REPLY_STATES.load(deps.storage, nonce)?;
+    SWAP_REPLY_STATES.remove(deps.storage, nonce);
+
+    let data = match msg.result {
+        SubMsgResult::Ok(SubMsgResponse { data, .. }) => data,
+        SubMsgResult::Err(reason) => {
+            return Err(ContractError::FailedSwap {
+                reason: format!("{:?}", reason),
+            })
+        }
+    };
+    let reported = data
+        .map(|b| decode_swap_response(&state.kind, state.token_in_amount, b))
+        .transpose()?;
+    let measured = match state.token_out_balance_before {
+        Some(before) => {
+            let after = deps
+                .querier
+                .query_balance(&env.contract.address, &state.token_out_denom)?
+                .amount;
+            Some(
+                after
+                    .checked_sub(before)
+                    .map_err(|e| cosmwasm_std::StdError::generic_err(e.to_string()))?,
+            )
+        }
+        None => None,
+    };
+    // (input actually spent, output received)
+    let (spent_in, amount) = match (reported, measured) {
+        (Some((_, reported)), Some(measured)) if reported != measured => {
+            return Err(ContractError::OutputMismatch { reported, measured })
+        }
+        (Some(reported), _) => reported,
+        // Exact-in swaps spend the whole input, so the balance delta is all that is needed
+        (None, Some(measured))
+            if matches!(state.kind, PendingSwapKind::Single | PendingSwapKind::Split) =>
+        {
+            (state.token_in_amount, measured)
+        }
+        _ => {
+            return Err(ContractError::FailedSwap {
+                reason: "missing swap response data".to_string(),
+            })
+        }
+    };
+
+    // Exact-out swaps may not use the whole maximum input; return the rest
+    let refund_in = state
+        .token_in_amount
+        .checked_sub(spent_in)
+        .map_err(|e| cosmwasm_std::StdError::generic_err(e.to_string()))?;
+
+    let cfg = CONFIG.load(deps.storage)?;
+    let mut msgs: Vec<SubMsg> = vec![];
+    let fee_in: Uint128 = state
+        .affiliate_payouts
+        .iter()
+        .map(|p| p.amount.amount)
+        .sum();
+    let mut affiliate_payouts = state.affiliate_payouts.clone();
+    let mut affiliate_out = Uint128::zero();
+    if let Some(output_fee) = &state.output_fee {
+        affiliate_out = compute_affiliate_fee(amount, output_fee.affiliate_bps);
+        let (fee_msgs, payouts) = distribute_fee(
+            deps.storage,
+            &cfg,
+            affiliate_out,
+            &state.token_out_denom,
+            &output_fee.recipients,
+        )?;
+        msgs.extend(fee_msgs.into_iter().map(SubMsg::new));
+        affiliate_payouts.extend(payouts);
+    }
+    let user_out = amount - affiliate_out;
+    if let Some(output_fee) = &state.output_fee {
+        if user_out < output_fee.token_out_min_amount {
+            return Err(ContractError::MinOutputNotMet {
+                min: output_fee.token_out_min_amount,
+                actual: user_out,
+            });
+        }
+    }
+
+    let affiliate_fee = match &state.output_fee {
+        Some(_) => Coin::new(affiliate_out.u128(), state.token_out_denom.clone()),
+        None => Coin::new(fee_in.u128(), state.token_in_denom.clone()),
+    };
+    add_stats(
+        deps.storage,
+        &state.affiliate_addr,
+        &state.token_in_denom,
+        Uint128::zero(),
+        spent_in,
+    )?;
+    add_stats(
+        deps.storage,
+        &state.affiliate_addr,
+        &state.token_out_denom,
+        affiliate_out,
+        Uint128::zero(),
+    )?;
+    let swap_id = record_swap(
+        deps.storage,
+        &env,
+        &state,
+        Coin::new(spent_in.u128(), state.token_in_denom.clone()),
+        Coin::new(user_out.u128(), state.token_out_denom.clone()),
+        affiliate_fee,
+    )?;
+
+    let response = SwapResponse {
+        original_sender: state.original_sender.to_string(),
+        recipient: state.recipient.to_string(),
+        token_out_denom: state.token_out_denom.clone(),
+        amount_sent_to_user: user_out,
+        amount_sent_to_affiliate: affiliate_out,
+        token_in_refunded: refund_in,
+        affiliate_payouts: affiliate_payouts
+            .into_iter()
+            .map(|p| AffiliatePayout {
+                recipient: p.recipient.into_string(),
+                amount: p.amount,
+            })
+            .collect(),
+    };
+    let resp = Response::new()
+        .add_submessages(msgs)
+        .add_attribute("token_out_amount", amount)
+        .add_attribute("affiliate_out_amount", affiliate_out)
+        .add_attribute("token_in_refund_amount", refund_in)
+        .add_attribute("swap_id", swap_id.to_string());
+
+    if let Some(batch_id) = state.batch {
+        shift_batch_snapshots(deps.storage, batch_id, &state, spent_in, amount, &resp)?;
+        let mut batch = BATCH_STATES.load(deps.storage, batch_id)?;
+        *batch.payout.entry(state.token_out_denom).or_default() += user_out;
+        *batch.payout.entry(state.token_in_denom).or_default() += refund_in;
+        batch.legs.push(response);
+        batch.pending -= 1;
+        if batch.pending > 0 {
+            BATCH_STATES.save(deps.storage, batch_id, &batch)?;
+            return Ok(resp);
+        }
+        return complete_batch(deps, batch_id, batch, resp);
+    }
+
+    let mut msgs: Vec<SubMsg> = vec![];
+    let mut attrs = vec![];
+    if !user_out.is_zero() {
+        if let Some(forward) = state.ibc_forward {
+            let amount = Coin::new(user_out.u128(), state.token_out_denom.clone());
+            let transfer = MsgTransfer {
+                source_port: "transfer".to_string(),
+                source_channel: forward.channel.clone(),
+                token: Some(amount.clone().into()),
+                sender: env.contract.address.to_string(),
+                receiver: forward.receiver,
+                timeout_height: None,
+                timeout_timestamp: forward.timeout_timestamp,
+                memo: forward.memo,
+            };
+            attrs.push(("ibc_channel", forward.channel.clone()));
+            PENDING_IBC_TRANSFERS.save(
+                deps.storage,
+                nonce,
+                &InFlightTransfer {
+                    channel: forward.channel,
+                    recovery_addr: state.recipient.clone(),
+                    amount,
+                },
+            )?;
+            msgs.push(SubMsg::reply_on_success(
+                transfer,
+                IBC_TRANSFER_REPLY_ID_START + nonce,
+            ));
+        } else if let Some(callback) = state.post_swap_action {
+            let amount = Coin::new(user_out.u128(), state.token_out_denom.clone());
+            attrs.push(("post_swap_action", callback.contract_addr.to_string()));
+            PENDING_CALLBACKS.save(
+                deps.storage,
+                nonce,
+                &PendingCallback {
+                    refund_addr: state.recipient.clone(),
+                    amount: amount.clone(),
+                },
+            )?;
+            msgs.push(SubMsg::reply_always(
+                WasmMsg::Execute {
+                    contract_addr: callback.contract_addr.into_string(),
+                    msg: callback.msg,
+                    funds: vec![amount],
+                },
+                POST_SWAP_REPLY_ID_START + nonce,
+            ));
+        } else {
+            msgs.push(SubMsg::new(BankMsg::Send {
+                to_address: state.recipient.to_string(),
+                amount: coins(user_out.u128(), state.token_out_denom.clone()),
+            }));
+        }
+    }
+    if !refund_in.is_zero() {
+        msgs.push(SubMsg::new(BankMsg::Send {
+            to_address: state.original_sender.to_string(),
+            amount: coins(refund_in.u128(), state.token_in_denom.clone()),
+        }));
+    }
+
+    Ok(resp
+        .add_submessages(msgs)
+        .set_data(cosmwasm_std::to_json_binary(&response)?)
+        .add_attributes(attrs))
+}
+
+/// Adds to the running fee and volume totals of `denom`, per affiliate and globally.
+fn add_stats(
+    storage: &mut dyn Storage,
+    affiliate: &Addr,
+    denom: &str,
+    fees: Uint128,
+    volume: Uint128,
+) -> StdResult<()> {
+    if fees.is_zero() && volume.is_zero() {
+        return Ok(());
+    }
+    let add = |stats: Option<DenomStats>| -> StdResult<DenomStats> {
+        let mut stats = stats.unwrap_or_default();
+        stats.fees_collected += fees;
+        stats.volume += volume;
+        Ok(stats)
+    };
+    AFFILIATE_STATS.update(storage, (affiliate, denom), add)?;
+    GLOBAL_STATS.update(storage, denom, add)?;
+    Ok(())
+}
+
+fn record_swap(
+    storage: &mut dyn Storage,
+    env: &Env,
+    state: &SwapReplyState,
+    token_in: Coin,
+    token_out: Coin,
+    affiliate_fee: Coin,
+) -> StdResult<u64> {
+    let id = SWAP_RECORD_COUNT.may_load(storage)?.unwrap_or_default() + 1;
+    SWAP_RECORD_COUNT.save(storage, &id)?;
+    swap_history().save(
+        storage,
+        id,
+        &SwapRecord {
+            id,
+            sender: state.original_sender.clone(),
+            affiliate: state.affiliate_addr.clone(),
+            affiliate_id: state.affiliate_id.clone(),
+            token_in,
+            token_out,
+            affiliate_fee,
+            height: env.block.height,
+            time: env.block.time,
+        },
+    )?;
+    Ok(id)
+}
+
+/// `(input spent, output received)` as reported by poolmanager's response data.
+fn decode_swap_response(
+    kind: &PendingSwapKind,
+    token_in_amount: Uint128,
+    data: Binary,
+) -> Result<(Uint128, Uint128), ContractError> {
+    Ok(match kind {
+        PendingSwapKind::Single => {
+            let res: MsgSwapExactAmountInResponse = data.try_into().map_err(ContractError::Std)?;
+            (token_in_amount, Uint128::from_str(&res.token_out_amount)?)
+        }
+        PendingSwapKind::Split => {
+            let res: MsgSplitRouteSwapExactAmountInResponse =
+                data.try_into().map_err(ContractError::Std)?;
+            (token_in_amount, Uint128::from_str(&res.token_out_amount)?)
+        }
+        PendingSwapKind::ExactOut { token_out_amount } => {
+            let res: MsgSwapExactAmountOutResponse = data.try_into().map_err(ContractError::Std)?;
+            (Uint128::from_str(&res.token_in_amount)?, *token_out_amount)
+        }
+        PendingSwapKind::SplitExactOut { token_out_amount } => {
+            let res: MsgSplitRouteSwapExactAmountOutResponse =
+                data.try_into().map_err(ContractError::Std)?;
+            (Uint128::from_str(&res.token_in_amount)?, *token_out_amount)
+        }
+    })
+}
+
+/// Total of `denom` in the bank sends queued on `resp`.
+fn queued_sends(resp: &Response, denom: &str) -> Uint128 {
+    resp.messages
+        .iter()
+        .filter_map(|m| match &m.msg {
+            CosmosMsg::Bank(BankMsg::Send { amount, .. }) => Some(amount),
+            _ => None,
+        })
+        .flatten()
+        .filter(|c| c.denom == denom)
+        .map(|c| c.amount)
+        .sum()
+}
+
+/// Contract balance of `denom` once the bank sends already queued on `resp` have run,
+/// i.e. just before a swap appended next is executed.
+fn balance_after_sends(
+    deps: &DepsMut,
+    contract: &Addr,
+    denom: &str,
+    resp: &Response,
+) -> StdResult<Uint128> {
+    let balance = deps.querier.query_balance(contract, denom)?.amount;
+    Ok(balance.saturating_sub(queued_sends(resp, denom)))
+}
+
+/// Batch legs still in flight were snapshotted before this leg ran. Account for what this
+/// leg left in the contract (its output), took out (its input) and is about to send.
+fn shift_batch_snapshots(
+    storage: &mut dyn Storage,
+    batch_id: u64,
+    state: &SwapReplyState,
+    spent_in: Uint128,
+    amount: Uint128,
+    resp: &Response,
+) -> StdResult<()> {
+    let legs = SWAP_REPLY_STATES
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (nonce, mut leg) in legs {
+        let Some(mut before) = leg.token_out_balance_before else {
+            continue;
+        };
+        if leg.batch != Some(batch_id) {
+            continue;
+        }
+        if leg.token_out_denom == state.token_out_denom {
+            before += amount;
+        }
+        if leg.token_out_denom == state.token_in_denom {
+            before = before.saturating_sub(spent_in);
+        }
+        before = before.saturating_sub(queued_sends(resp, &leg.token_out_denom));
+        leg.token_out_balance_before = Some(before);
+        SWAP_REPLY_STATES.save(storage, nonce, &leg)?;
+    }
+    Ok(())
+}
+
+/// Pays out the merged outputs and refunds of a batch once its last leg has replied.
+fn complete_batch(
+    deps: DepsMut,
+    batch_id: u64,
+    batch: BatchState,
+    resp: Response,
+) -> Result<Response, ContractError> {
+    BATCH_STATES.remove(deps.storage, batch_id);
+    let payout: Vec<Coin> = batch
+        .payout
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(denom, amount)| Coin { denom, amount })
+        .collect();
+    let mut resp = resp;
+    if !payout.is_empty() {
+        resp = resp.add_message(BankMsg::Send {
+            to_address: batch.recipient.to_string(),
+            amount: payout.clone(),
+        });
+    }
+    let response = BatchSwapResponse {
+        recipient: batch.recipient.into_string(),
+        payout,
+        swaps: batch.legs,
+    };
+    Ok(resp
+        .set_data(cosmwasm_std::to_json_binary(&response)?)
+        .add_attribute("batch_complete", batch_id.to_string()))
+}
+
+/// Proxies a swap for an ibc-hooks caller, acting on behalf of `fallback_address` locally.
+pub fn ibc_hooks_proxy_swap(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    swap: ProxySwap,
+    affiliate_id: Option<String>,
+    ibc_forward: IbcForward,
+    fallback_address: String,
+) -> Result<Response, ContractError> {
+    let fallback = deps.api.addr_validate(&fallback_address)?;
+    let hooks_sender = info.sender;
+    // The derived hooks sender is unreachable, so every local refund goes to the fallback.
+    // Fee overrides still follow the real sender: the fallback is caller-supplied.
+    let info = MessageInfo {
+        sender: fallback,
+        funds: info.funds,
+    };
+    let resp = proxy_swap_with_fee(
+        deps,
+        env,
+        info,
+        swap,
+        SwapOptions {
+            affiliate_id,
+            recipient: None,
+            ibc_forward: Some(ibc_forward),
+            post_swap_action: None,
+            deadline: None,
+            fee_payer: Some(hooks_sender.clone()),
+        },
+    )?;
+    Ok(resp
+        .add_attribute("hooks_sender", hooks_sender)
+        .add_attribute("fallback_address", fallback_address))
+}
+
+/// Refunds the output to the user when the post-swap callback fails.
+pub fn handle_post_swap_reply(
+    deps: DepsMut,
+    nonce: u64,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let pending = PENDING_CALLBACKS.load(deps.storage, nonce)?;
+    PENDING_CALLBACKS.remove(deps.storage, nonce);
+
+    match msg.result {
+        SubMsgResult::Ok(_) => Ok(Response::new()),
+        SubMsgResult::Err(reason) => Ok(Response::new()
+            .add_message(BankMsg::Send {
+                to_address: pending.refund_addr.to_string(),
+                amount: vec![pending.amount],
+            })
+            .add_attribute("action", "post_swap_action_failed")
+            .add_attribute("refund_addr", pending.refund_addr)
+            .add_attribute("reason", reason)),
+    }
+}
+
+/// Records the sequence of a forwarded output so its ack or timeout can be matched.
+pub fn handle_ibc_transfer_reply(
+    deps: DepsMut,
+    nonce: u64,
+    msg: Reply,
+) -> Result<Response, ContractError> {
+    let transfer = PENDING_IBC_TRANSFERS.load(deps.storage, nonce)?;
+    PENDING_IBC_TRANSFERS.remove(deps.storage, nonce);
+
+    let sequence = match msg.result {
+        SubMsgResult::Ok(SubMsgResponse { data: Some(b), .. }) => {
+            let res: MsgTransferResponse = b.try_into().map_err(ContractError::Std)?;
+            res.sequence
+        }
+        _ => {
+            return Err(ContractError::InvalidIbcForward {
+                reason: "missing transfer sequence".to_string(),
+            })
+        }
+    };
+    IBC_TRANSFERS.save(deps.storage, (&transfer.channel, sequence), &transfer)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "ibc_forward")
+        .add_attribute("ibc_channel", transfer.channel)
+        .add_attribute("ibc_sequence", sequence.to_string()))
+}
+
+/// ibc-hooks callback: failed or timed-out forwards become claimable by their recovery address.
+pub fn ibc_lifecycle_complete(
+    deps: DepsMut,
+    msg: IbcLifecycleComplete,
+) -> Result<Response, ContractError> {
+    let (channel, sequence, failed) = match msg {
+        IbcLifecycleComplete::IbcAck {
+            channel,
+            sequence,
+            success,
+            ..
+        } => (channel, sequence, !success),
+        IbcLifecycleComplete::IbcTimeout { channel, sequence } => (channel, sequence, true),
+    };
+    let resp = Response::new()
+        .add_attribute("action", "ibc_lifecycle_complete")
+        .add_attribute("ibc_channel", &channel)
+        .add_attribute("ibc_sequence", sequence.to_string());
+    let Some(transfer) = IBC_TRANSFERS.may_load(deps.storage, (&channel, sequence))? else {
+        return Ok(resp.add_attribute("status", "unknown"));
+    };
+    IBC_TRANSFERS.remove(deps.storage, (&channel, sequence));
+    if !failed {
+        return Ok(resp.add_attribute("status", "delivered"));
+    }
+
+    RECOVERABLE.update(
+        deps.storage,
+        (&transfer.recovery_addr, &transfer.amount.denom),
+        |prev| -> StdResult<_> { Ok(prev.unwrap_or_default() + transfer.amount.amount) },
+    )?;
+    Ok(resp
+        .add_attribute("status", "recoverable")
+        .add_attribute("recovery_addr", transfer.recovery_addr))
+}
+
+pub fn claim_recoverable(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let funds = RECOVERABLE
+        .prefix(&info.sender)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+    if funds.is_empty() {
+        return Err(ContractError::NothingToClaim {});
+    }
+    for coin in &funds {
+        RECOVERABLE.remove(deps.storage, (&info.sender, &coin.denom));
+    }
+
+    let resp = Response::new()
+        .add_message(BankMsg::Send {
+            to_address: info.sender.to_string(),
+            amount: funds,
+        })
+        .add_attribute("action", "claim_recoverable");
+    refund_leftover(&cfg, &info, &[], resp)
 }
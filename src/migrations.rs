@@ -0,0 +1,56 @@
+use cosmwasm_std::{Addr, Storage};
+use cw_storage_plus::Item;
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ContractError;
+use crate::state::{Config, FeeSide, CONFIG, DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS};
+
+/// Config as stored by 0.1.x.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+struct ConfigV0_1 {
+    owner: Addr,
+    affiliate_addr: Addr,
+    affiliate_bps: u16,
+}
+
+const CONFIG_V0_1: Item<ConfigV0_1> = Item::new("config");
+/// Single reply slot used by 0.1.x, replaced by nonce-keyed reply state.
+const SWAP_REPLY_STATE_V0_1: Item<cosmwasm_std::Empty> = Item::new("swap_reply_state");
+
+/// Runs every migration step newer than `from`, oldest first.
+pub fn migrate_from(storage: &mut dyn Storage, from: &Version) -> Result<(), ContractError> {
+    if *from < Version::new(0, 2, 0) {
+        v0_2_0(storage)?;
+    }
+    Ok(())
+}
+
+/// 0.2.0 makes the owner optional and adds these `Config` fields, all off or empty
+/// unless noted: fee enforcement, strict funds, weighted recipients, fee side (input),
+/// a pause guardian, fee accrual, the fee conversion slippage (100 bps), balance delta
+/// accounting and the TWAP guard.
+fn v0_2_0(storage: &mut dyn Storage) -> Result<(), ContractError> {
+    let old = CONFIG_V0_1.load(storage)?;
+    CONFIG.save(
+        storage,
+        &Config {
+            owner: Some(old.owner),
+            affiliate_addr: old.affiliate_addr,
+            affiliate_bps: old.affiliate_bps,
+            enforce_affiliate_bps: false,
+            strict_funds: false,
+            affiliate_recipients: vec![],
+            affiliate_fee_side: FeeSide::Input,
+            guardian: None,
+            accrue_fees: false,
+            conversion_max_slippage_bps: DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS,
+            balance_delta_accounting: false,
+            twap_guard: None,
+        },
+    )?;
+    // Reply state never outlives a transaction, and its layout changed
+    SWAP_REPLY_STATE_V0_1.remove(storage);
+    Ok(())
+}
@@ -1,14 +1,21 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Coin, Uint128};
+use cosmwasm_std::{Binary, Coin, Timestamp, Uint128};
+use cw_utils::Expiration;
 use osmosis_std::types::osmosis::poolmanager::v1beta1::{
-    SwapAmountInRoute, SwapAmountInSplitRoute,
+    SwapAmountInRoute, SwapAmountInSplitRoute, SwapAmountOutRoute, SwapAmountOutSplitRoute,
 };
 
+pub use crate::state::{FeeSide, PausableOperation, TwapGuard};
+
 #[cw_serde]
 pub struct InstantiateMsg {
     pub owner: String,
     pub affiliate_addr: String,
     pub affiliate_bps: u16, // out of 10_000 (basis points)
+    #[serde(default)]
+    pub enforce_affiliate_bps: bool,
+    #[serde(default)]
+    pub strict_funds: bool,
 }
 
 #[cw_serde]
@@ -23,40 +30,415 @@ pub enum ProxySwap {
         token_in_denom: String,
         token_out_min_amount: Uint128,
     },
+    SwapExactAmountOut {
+        routes: Vec<SwapAmountOutRoute>,
+        token_in_max_amount: Uint128,
+        token_out: Coin,
+    },
+    SplitRouteSwapExactAmountOut {
+        routes: Vec<SwapAmountOutSplitRoute>,
+        token_out_denom: String,
+        token_in_max_amount: Uint128,
+    },
 }
 
+/// Share of the affiliate fee paid to `addr`; weights across recipients sum to 10_000.
 #[cw_serde]
+pub struct FeeRecipient {
+    pub addr: String,
+    pub weight: u16,
+}
+
+/// Sends the swap output over ICS-20 instead of a local bank send.
+#[cw_serde]
+pub struct IbcForward {
+    pub channel: String,
+    pub receiver: String,
+    /// Seconds after the swap; defaults to 10 minutes, at most 7 days.
+    pub timeout: Option<u64>,
+    /// JSON object memo; the contract adds its own `ibc_callback` entry.
+    pub memo: Option<String>,
+}
+
+/// Delivers the swap output somewhere other than a bank send.
+#[cw_serde]
+pub enum PostSwapAction {
+    /// Executes `msg` on `contract_addr` with the output attached as funds.
+    Wasm { contract_addr: String, msg: Binary },
+}
+
+#[cw_serde]
+#[allow(clippy::large_enum_variant)]
 pub enum ExecuteMsg {
     ProxySwapWithFee {
         swap: ProxySwap,
+        /// Registered partner to pay; the default affiliate from config when omitted.
+        affiliate_id: Option<String>,
+        /// Receives the swap output instead of the sender.
+        recipient: Option<String>,
+        /// Forwards the output over IBC; `recipient` (or the sender) can claim it back on failure.
+        ibc_forward: Option<IbcForward>,
+        /// Hands the output to a contract; refunded to `recipient` (or the sender) if it fails.
+        post_swap_action: Option<PostSwapAction>,
+        /// Rejects the swap once the block time or height is past this.
+        deadline: Option<Expiration>,
+    },
+    RegisterAffiliate {
+        id: String,
+        affiliate_addr: String,
+        affiliate_bps: u16,
+        recipients: Option<Vec<FeeRecipient>>,
+        fee_side: Option<FeeSide>,
     },
+    /// Updates the registered affiliate `id`, or the default affiliate when omitted.
     UpdateAffiliate {
+        id: Option<String>,
         affiliate_addr: String,
         affiliate_bps: u16,
+        recipients: Option<Vec<FeeRecipient>>,
+        fee_side: Option<FeeSide>,
+    },
+    RemoveAffiliate {
+        id: String,
+    },
+    /// Charges `address` `bps` instead of the affiliate's rate (0 exempts it), or removes
+    /// the override when `None`.
+    SetFeeOverride {
+        address: String,
+        bps: Option<u16>,
     },
+    /// Proposes `new_owner`, who must accept before `expiry` (if any).
     TransferOwnership {
         new_owner: String,
+        expiry: Option<Expiration>,
+    },
+    AcceptOwnership {},
+    CancelOwnershipTransfer {},
+    /// Permanently removes the owner; all owner-only messages become unusable.
+    RenounceOwnership {},
+    UpdateConfig {
+        strict_funds: Option<bool>,
+        enforce_affiliate_bps: Option<bool>,
+        accrue_fees: Option<bool>,
+        conversion_max_slippage_bps: Option<u16>,
+        balance_delta_accounting: Option<bool>,
+    },
+    /// Lets `address` claim the sender's accrued fees, or revokes the delegation when `None`.
+    SetFeeWithdrawAddress {
+        address: Option<String>,
+    },
+    /// Pays out accrued fees of `affiliate` (default: the sender) for `denoms` (default: all).
+    /// Callable by the affiliate or its withdraw address; funds go to the withdraw address if set.
+    ClaimFees {
+        affiliate: Option<String>,
+        denoms: Option<Vec<String>>,
+    },
+    /// Has the sender's accrued fees converted into `denom` by `ConvertFees`, or stops
+    /// converting them when `None`.
+    SetSettlementDenom {
+        denom: Option<String>,
+    },
+    /// Owner only. Sets or removes the route used to convert accrued `denom_in` fees
+    /// into the settlement denom `denom_out`.
+    SetConversionRoute {
+        denom_in: String,
+        denom_out: String,
+        route: Option<Vec<SwapAmountInRoute>>,
+    },
+    /// Permissionless. Swaps accrued fees into each recipient's settlement denom where a
+    /// route exists, with the minimum output derived from the pools' TWAP. Handles at
+    /// most `limit` recipients after `start_after`.
+    ConvertFees {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Owner only. Replaces the given pool and denom allow/deny lists; an empty allow list
+    /// allows everything not denied.
+    UpdateRouteLists {
+        allowed_pools: Option<Vec<u64>>,
+        denied_pools: Option<Vec<u64>>,
+        allowed_denoms: Option<Vec<String>>,
+        denied_denoms: Option<Vec<String>>,
+    },
+    /// Owner only. Enables the TWAP minimum-output guard, or disables it when `None`.
+    SetTwapGuard {
+        guard: Option<TwapGuard>,
+    },
+    /// Sets or clears the guardian allowed to pause operations.
+    SetGuardian {
+        guardian: Option<String>,
+    },
+    /// Owner or guardian. Swap replies already in flight still complete.
+    Pause {
+        operations: Vec<PausableOperation>,
+    },
+    /// Owner only.
+    Unpause {
+        operations: Vec<PausableOperation>,
+    },
+    /// Swaps several inputs at once and pays all outputs to the sender in one transfer.
+    BatchProxySwap {
+        swaps: Vec<ProxySwap>,
+        affiliate_id: Option<String>,
+    },
+    /// Entry point for swaps arriving through an ibc-hooks memo, where the sender is a
+    /// derived intermediate address. The output is sent back over `ibc_forward`; refunds
+    /// and failed return transfers go to `fallback_address`.
+    IbcHooksProxySwap {
+        swap: ProxySwap,
+        affiliate_id: Option<String>,
+        ibc_forward: IbcForward,
+        fallback_address: String,
+    },
+    /// Withdraws funds returned by failed or timed-out IBC forwards.
+    ClaimRecoverable {},
+}
+
+/// Callbacks delivered by the ibc-hooks module for transfers this contract sent.
+#[cw_serde]
+pub enum SudoMsg {
+    IbcLifecycleComplete(IbcLifecycleComplete),
+}
+
+#[cw_serde]
+pub enum IbcLifecycleComplete {
+    IbcAck {
+        channel: String,
+        sequence: u64,
+        ack: String,
+        success: bool,
+    },
+    IbcTimeout {
+        channel: String,
+        sequence: u64,
     },
 }
 
+#[cw_serde]
+pub struct MigrateMsg {}
+
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
     #[returns(ConfigResponse)]
     Config {},
+    #[returns(OwnershipResponse)]
+    Ownership {},
+    #[returns(PauseStatusResponse)]
+    PauseStatus {},
+    #[returns(RouteListsResponse)]
+    RouteLists {},
+    #[returns(AffiliateResponse)]
+    Affiliate { id: String },
+    #[returns(AffiliatesResponse)]
+    Affiliates {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    #[returns(FeeOverrideResponse)]
+    FeeOverride { address: String },
+    /// Senders with a fee override, ordered by address.
+    #[returns(FeeOverridesResponse)]
+    FeeOverrides {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    #[returns(SwapRecordResponse)]
+    Swap { id: u64 },
+    /// Swap history of `sender`, oldest first.
+    #[returns(SwapsResponse)]
+    SwapsBySender {
+        sender: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Swaps that paid the affiliate at address `affiliate`, oldest first.
+    #[returns(SwapsResponse)]
+    SwapsByAffiliate {
+        affiliate: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Fee and volume totals per denom for the affiliate at address `affiliate`.
+    #[returns(StatsResponse)]
+    AffiliateStats {
+        affiliate: String,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Fee and volume totals per denom across all affiliates.
+    #[returns(StatsResponse)]
+    GlobalStats {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Fees accrued to `affiliate` and not yet claimed.
+    #[returns(PendingFeesResponse)]
+    PendingFees { affiliate: String },
+    /// Route used to convert accrued `denom_in` fees into `denom_out`.
+    #[returns(ConversionRouteResponse)]
+    ConversionRoute { denom_in: String, denom_out: String },
+    /// Funds from failed IBC forwards claimable by `address`.
+    #[returns(RecoverableResponse)]
+    Recoverable { address: String },
+    /// Quotes a `ProxySwapWithFee` as if sent with `gross_funds` attached.
+    #[returns(SimulateProxySwapResponse)]
+    SimulateProxySwap {
+        swap: ProxySwap,
+        gross_funds: Vec<Coin>,
+        affiliate_id: Option<String>,
+        /// Applies this sender's fee override, if any.
+        sender: Option<String>,
+    },
 }
 
 #[cw_serde]
 pub struct ConfigResponse {
-    pub owner: String,
+    pub owner: Option<String>,
     pub affiliate_addr: String,
     pub affiliate_bps: u16,
+    pub enforce_affiliate_bps: bool,
+    pub strict_funds: bool,
+    pub affiliate_recipients: Vec<FeeRecipient>,
+    pub affiliate_fee_side: FeeSide,
+    pub accrue_fees: bool,
+    pub conversion_max_slippage_bps: u16,
+    pub balance_delta_accounting: bool,
+    pub twap_guard: Option<TwapGuard>,
+}
+
+#[cw_serde]
+pub struct OwnershipResponse {
+    pub owner: Option<String>,
+    pub pending_owner: Option<String>,
+    pub pending_expiry: Option<Expiration>,
+}
+
+#[cw_serde]
+pub struct PauseStatusResponse {
+    pub swaps_paused: bool,
+    pub config_paused: bool,
+    pub guardian: Option<String>,
+}
+
+#[cw_serde]
+pub struct RouteListsResponse {
+    pub allowed_pools: Vec<u64>,
+    pub denied_pools: Vec<u64>,
+    pub allowed_denoms: Vec<String>,
+    pub denied_denoms: Vec<String>,
+}
+
+#[cw_serde]
+pub struct AffiliateResponse {
+    pub id: String,
+    pub affiliate_addr: String,
+    pub affiliate_bps: u16,
+    pub recipients: Vec<FeeRecipient>,
+    pub fee_side: FeeSide,
+}
+
+#[cw_serde]
+pub struct AffiliatesResponse {
+    pub affiliates: Vec<AffiliateResponse>,
+}
+
+#[cw_serde]
+pub struct FeeOverrideResponse {
+    pub address: String,
+    /// `None` when the sender pays the affiliate's own rate.
+    pub bps: Option<u16>,
+}
+
+#[cw_serde]
+pub struct FeeOverridesResponse {
+    pub overrides: Vec<FeeOverrideResponse>,
+}
+
+#[cw_serde]
+pub struct SwapRecordResponse {
+    pub id: u64,
+    pub sender: String,
+    pub affiliate: String,
+    pub affiliate_id: Option<String>,
+    pub token_in: Coin,
+    pub token_out: Coin,
+    pub affiliate_fee: Coin,
+    pub height: u64,
+    pub time: Timestamp,
+}
+
+#[cw_serde]
+pub struct SwapsResponse {
+    pub swaps: Vec<SwapRecordResponse>,
+}
+
+#[cw_serde]
+pub struct DenomStatsResponse {
+    pub denom: String,
+    pub fees_collected: Uint128,
+    pub volume: Uint128,
+}
+
+#[cw_serde]
+pub struct StatsResponse {
+    pub stats: Vec<DenomStatsResponse>,
+}
+
+#[cw_serde]
+pub struct PendingFeesResponse {
+    pub affiliate: String,
+    pub withdraw_address: Option<String>,
+    pub settlement_denom: Option<String>,
+    pub fees: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct ConversionRouteResponse {
+    pub denom_in: String,
+    pub denom_out: String,
+    pub route: Option<Vec<SwapAmountInRoute>>,
+}
+
+#[cw_serde]
+pub struct RecoverableResponse {
+    pub funds: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct SimulateProxySwapResponse {
+    pub fee_side: FeeSide,
+    /// Fee in the input denom for input-side affiliates, in the output denom otherwise.
+    pub affiliate_fee: Coin,
+    pub net_token_in: Coin,
+    /// Expected amount the caller receives, after any output-side fee.
+    pub expected_token_out: Coin,
+    /// Expected unspent input returned on exact-amount-out swaps.
+    pub expected_token_in_refund: Uint128,
 }
 
 #[cw_serde]
 pub struct SwapResponse {
     pub original_sender: String,
+    pub recipient: String,
     pub token_out_denom: String,
     pub amount_sent_to_user: Uint128,
     pub amount_sent_to_affiliate: Uint128,
+    pub token_in_refunded: Uint128,
+    pub affiliate_payouts: Vec<AffiliatePayout>,
+}
+
+/// Data set by the reply of the last leg of a `BatchProxySwap`.
+#[cw_serde]
+pub struct BatchSwapResponse {
+    pub recipient: String,
+    /// Outputs and exact-out input refunds, merged per denom.
+    pub payout: Vec<Coin>,
+    pub swaps: Vec<SwapResponse>,
+}
+
+#[cw_serde]
+pub struct AffiliatePayout {
+    pub recipient: String,
+    pub amount: Coin,
 }
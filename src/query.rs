@@ -1,14 +1,417 @@
-use cosmwasm_std::{Deps, StdResult};
+use std::str::FromStr;
 
-use crate::msg::ConfigResponse;
-use crate::state::CONFIG;
+use cosmwasm_std::{Addr, Coin, Deps, Env, Order, StdError, StdResult, Uint128};
+use cw_storage_plus::Bound;
+use osmosis_std::types::osmosis::poolmanager::v1beta1::PoolmanagerQuerier;
+
+use crate::error::ContractError;
+use crate::execute::{compute_affiliate_fee, quote_swap, SwapQuote};
+use crate::msg::{
+    AffiliateResponse, AffiliatesResponse, ConfigResponse, ConversionRouteResponse,
+    DenomStatsResponse, FeeOverrideResponse, FeeOverridesResponse, FeeRecipient, OwnershipResponse,
+    PauseStatusResponse, PendingFeesResponse, ProxySwap, RecoverableResponse, RouteListsResponse,
+    SimulateProxySwapResponse, StatsResponse, SwapRecordResponse, SwapsResponse,
+};
+use crate::state::{
+    swap_history, AffiliateInfo, DenomStats, FeeShare, SwapRecord, ACCRUED_FEES, AFFILIATES,
+    AFFILIATE_STATS, CONFIG, CONVERSION_ROUTES, FEE_OVERRIDES, FEE_WITHDRAW_ADDRS, GLOBAL_STATS,
+    PAUSE_STATE, PENDING_OWNERSHIP, RECOVERABLE, ROUTE_LISTS, SETTLEMENT_DENOMS,
+};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 pub fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
     let cfg = CONFIG.load(deps.storage)?;
     Ok(ConfigResponse {
-        owner: cfg.owner.into_string(),
+        owner: cfg.owner.map(Addr::into_string),
         affiliate_addr: cfg.affiliate_addr.into_string(),
         affiliate_bps: cfg.affiliate_bps,
+        enforce_affiliate_bps: cfg.enforce_affiliate_bps,
+        strict_funds: cfg.strict_funds,
+        affiliate_recipients: fee_recipients(cfg.affiliate_recipients),
+        affiliate_fee_side: cfg.affiliate_fee_side,
+        accrue_fees: cfg.accrue_fees,
+        conversion_max_slippage_bps: cfg.conversion_max_slippage_bps,
+        balance_delta_accounting: cfg.balance_delta_accounting,
+        twap_guard: cfg.twap_guard,
+    })
+}
+
+pub fn query_route_lists(deps: Deps) -> StdResult<RouteListsResponse> {
+    let lists = ROUTE_LISTS.may_load(deps.storage)?.unwrap_or_default();
+    Ok(RouteListsResponse {
+        allowed_pools: lists.allowed_pools,
+        denied_pools: lists.denied_pools,
+        allowed_denoms: lists.allowed_denoms,
+        denied_denoms: lists.denied_denoms,
+    })
+}
+
+pub fn query_ownership(deps: Deps) -> StdResult<OwnershipResponse> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let pending = PENDING_OWNERSHIP.may_load(deps.storage)?;
+    Ok(OwnershipResponse {
+        owner: cfg.owner.map(Addr::into_string),
+        pending_owner: pending.as_ref().map(|p| p.new_owner.to_string()),
+        pending_expiry: pending.and_then(|p| p.expiry),
+    })
+}
+
+pub fn query_pause_status(deps: Deps) -> StdResult<PauseStatusResponse> {
+    let cfg = CONFIG.load(deps.storage)?;
+    let paused = PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default();
+    Ok(PauseStatusResponse {
+        swaps_paused: paused.swaps,
+        config_paused: paused.config,
+        guardian: cfg.guardian.map(Addr::into_string),
+    })
+}
+
+fn fee_recipients(shares: Vec<FeeShare>) -> Vec<FeeRecipient> {
+    shares
+        .into_iter()
+        .map(|s| FeeRecipient {
+            addr: s.addr.into_string(),
+            weight: s.weight,
+        })
+        .collect()
+}
+
+fn affiliate_response(id: String, info: AffiliateInfo) -> AffiliateResponse {
+    AffiliateResponse {
+        id,
+        affiliate_addr: info.affiliate_addr.into_string(),
+        affiliate_bps: info.affiliate_bps,
+        recipients: fee_recipients(info.recipients),
+        fee_side: info.fee_side,
+    }
+}
+
+pub fn query_affiliate(deps: Deps, id: String) -> StdResult<AffiliateResponse> {
+    let info = AFFILIATES.load(deps.storage, id.clone())?;
+    Ok(affiliate_response(id, info))
+}
+
+pub fn query_affiliates(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<AffiliatesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let affiliates = AFFILIATES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(id, info)| affiliate_response(id, info)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(AffiliatesResponse { affiliates })
+}
+
+pub fn query_fee_override(deps: Deps, address: String) -> StdResult<FeeOverrideResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let bps = FEE_OVERRIDES.may_load(deps.storage, &address)?;
+    Ok(FeeOverrideResponse {
+        address: address.into_string(),
+        bps,
+    })
+}
+
+pub fn query_fee_overrides(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<FeeOverridesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after
+        .map(|addr| deps.api.addr_validate(&addr))
+        .transpose()?;
+    let start = start_after.as_ref().map(Bound::exclusive);
+    let overrides = FEE_OVERRIDES
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            item.map(|(address, bps)| FeeOverrideResponse {
+                address: address.into_string(),
+                bps: Some(bps),
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(FeeOverridesResponse { overrides })
+}
+
+fn swap_record_response(record: SwapRecord) -> SwapRecordResponse {
+    SwapRecordResponse {
+        id: record.id,
+        sender: record.sender.into_string(),
+        affiliate: record.affiliate.into_string(),
+        affiliate_id: record.affiliate_id,
+        token_in: record.token_in,
+        token_out: record.token_out,
+        affiliate_fee: record.affiliate_fee,
+        height: record.height,
+        time: record.time,
+    }
+}
+
+pub fn query_swap(deps: Deps, id: u64) -> StdResult<SwapRecordResponse> {
+    let record = swap_history().load(deps.storage, id)?;
+    Ok(swap_record_response(record))
+}
+
+pub fn query_swaps_by_sender(
+    deps: Deps,
+    sender: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<SwapsResponse> {
+    let sender = deps.api.addr_validate(&sender)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let swaps = swap_history()
+        .idx
+        .sender
+        .prefix(sender)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, record)| swap_record_response(record)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(SwapsResponse { swaps })
+}
+
+pub fn query_swaps_by_affiliate(
+    deps: Deps,
+    affiliate: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<SwapsResponse> {
+    let affiliate = deps.api.addr_validate(&affiliate)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let swaps = swap_history()
+        .idx
+        .affiliate
+        .prefix(affiliate)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, record)| swap_record_response(record)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(SwapsResponse { swaps })
+}
+
+fn denom_stats_response(denom: String, stats: DenomStats) -> DenomStatsResponse {
+    DenomStatsResponse {
+        denom,
+        fees_collected: stats.fees_collected,
+        volume: stats.volume,
+    }
+}
+
+pub fn query_affiliate_stats(
+    deps: Deps,
+    affiliate: String,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<StatsResponse> {
+    let affiliate = deps.api.addr_validate(&affiliate)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+    let stats = AFFILIATE_STATS
+        .prefix(&affiliate)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(denom, stats)| denom_stats_response(denom, stats)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StatsResponse { stats })
+}
+
+pub fn query_global_stats(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<StatsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.as_deref().map(Bound::exclusive);
+    let stats = GLOBAL_STATS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(denom, stats)| denom_stats_response(denom, stats)))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StatsResponse { stats })
+}
+
+pub fn query_pending_fees(deps: Deps, affiliate: String) -> StdResult<PendingFeesResponse> {
+    let affiliate = deps.api.addr_validate(&affiliate)?;
+    let fees = ACCRUED_FEES
+        .prefix(&affiliate)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+    let withdraw_address = FEE_WITHDRAW_ADDRS.may_load(deps.storage, &affiliate)?;
+    let settlement_denom = SETTLEMENT_DENOMS.may_load(deps.storage, &affiliate)?;
+    Ok(PendingFeesResponse {
+        affiliate: affiliate.into_string(),
+        withdraw_address: withdraw_address.map(Addr::into_string),
+        settlement_denom,
+        fees,
     })
 }
 
+pub fn query_conversion_route(
+    deps: Deps,
+    denom_in: String,
+    denom_out: String,
+) -> StdResult<ConversionRouteResponse> {
+    let route = CONVERSION_ROUTES.may_load(deps.storage, (&denom_in, &denom_out))?;
+    Ok(ConversionRouteResponse {
+        denom_in,
+        denom_out,
+        route,
+    })
+}
+
+pub fn query_recoverable(deps: Deps, address: String) -> StdResult<RecoverableResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let funds = RECOVERABLE
+        .prefix(&address)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(RecoverableResponse { funds })
+}
+
+/// Poolmanager estimate of `(input spent, output received)` for a prepared swap.
+fn estimate_swap(deps: Deps, swap: &ProxySwap, net_in: Uint128) -> StdResult<(Uint128, Uint128)> {
+    let querier = PoolmanagerQuerier::new(&deps.querier);
+    let first_pool = |pool_id: Option<u64>| {
+        pool_id.ok_or_else(|| StdError::generic_err("swap routes must not be empty"))
+    };
+    match swap {
+        ProxySwap::SwapExactAmountIn {
+            routes, token_in, ..
+        } => {
+            let pool_id = first_pool(routes.first().map(|r| r.pool_id))?;
+            let token_in = Coin::new(net_in.u128(), token_in.denom.clone()).to_string();
+            let res = if routes.len() == 1 {
+                querier.estimate_single_pool_swap_exact_amount_in(
+                    pool_id,
+                    token_in,
+                    routes[0].token_out_denom.clone(),
+                )?
+            } else {
+                querier.estimate_swap_exact_amount_in(pool_id, token_in, routes.clone())?
+            };
+            Ok((net_in, Uint128::from_str(&res.token_out_amount)?))
+        }
+        ProxySwap::SplitRouteSwapExactAmountIn {
+            routes,
+            token_in_denom,
+            ..
+        } => {
+            let mut total_out = Uint128::zero();
+            for route in routes {
+                let pool_id = first_pool(route.pools.first().map(|p| p.pool_id))?;
+                let token_in = format!("{}{}", route.token_in_amount, token_in_denom);
+                let res = querier.estimate_swap_exact_amount_in(
+                    pool_id,
+                    token_in,
+                    route.pools.clone(),
+                )?;
+                total_out += Uint128::from_str(&res.token_out_amount)?;
+            }
+            Ok((net_in, total_out))
+        }
+        ProxySwap::SwapExactAmountOut {
+            routes, token_out, ..
+        } => {
+            let pool_id = first_pool(routes.first().map(|r| r.pool_id))?;
+            let res = if routes.len() == 1 {
+                querier.estimate_single_pool_swap_exact_amount_out(
+                    pool_id,
+                    routes[0].token_in_denom.clone(),
+                    token_out.to_string(),
+                )?
+            } else {
+                querier.estimate_swap_exact_amount_out(
+                    pool_id,
+                    routes.clone(),
+                    token_out.to_string(),
+                )?
+            };
+            Ok((Uint128::from_str(&res.token_in_amount)?, token_out.amount))
+        }
+        ProxySwap::SplitRouteSwapExactAmountOut {
+            routes,
+            token_out_denom,
+            ..
+        } => {
+            let mut total_in = Uint128::zero();
+            let mut total_out = Uint128::zero();
+            for route in routes {
+                let pool_id = first_pool(route.pools.first().map(|p| p.pool_id))?;
+                let token_out = format!("{}{}", route.token_out_amount, token_out_denom);
+                let res = querier.estimate_swap_exact_amount_out(
+                    pool_id,
+                    route.pools.clone(),
+                    token_out,
+                )?;
+                total_in += Uint128::from_str(&res.token_in_amount)?;
+                total_out += Uint128::from_str(&route.token_out_amount)?;
+            }
+            Ok((total_in, total_out))
+        }
+    }
+}
+
+pub fn query_simulate_proxy_swap(
+    deps: Deps,
+    env: Env,
+    swap: ProxySwap,
+    gross_funds: Vec<Coin>,
+    affiliate_id: Option<String>,
+    sender: Option<String>,
+) -> StdResult<SimulateProxySwapResponse> {
+    let sender = sender.map(|s| deps.api.addr_validate(&s)).transpose()?;
+    let SwapQuote {
+        prepared,
+        affiliate,
+        output_fee_bps,
+        affiliate_in,
+    } = quote_swap(
+        deps.storage,
+        &env.contract.address,
+        &gross_funds,
+        swap.clone(),
+        affiliate_id.as_deref(),
+        sender.as_ref(),
+    )
+    .map_err(|e: ContractError| StdError::generic_err(e.to_string()))?;
+
+    let (spent_in, token_out) = if prepared.token_in_amount.is_zero() {
+        (Uint128::zero(), Uint128::zero())
+    } else {
+        estimate_swap(deps, &swap, prepared.token_in_amount)?
+    };
+    let affiliate_fee = match output_fee_bps {
+        Some(bps) => Coin::new(
+            compute_affiliate_fee(token_out, bps).u128(),
+            prepared.token_out_denom.clone(),
+        ),
+        None => Coin::new(affiliate_in.u128(), prepared.token_in_denom.clone()),
+    };
+    let user_out = match output_fee_bps {
+        Some(_) => token_out - affiliate_fee.amount,
+        None => token_out,
+    };
+
+    Ok(SimulateProxySwapResponse {
+        fee_side: affiliate.fee_side,
+        affiliate_fee,
+        net_token_in: Coin::new(
+            prepared.token_in_amount.u128(),
+            prepared.token_in_denom.clone(),
+        ),
+        expected_token_out: Coin::new(user_out.u128(), prepared.token_out_denom),
+        expected_token_in_refund: prepared.token_in_amount.saturating_sub(spent_in),
+    })
+}
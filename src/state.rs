@@ -1,27 +1,367 @@
-use cosmwasm_std::Addr;
-use cw_storage_plus::Item;
+use std::collections::BTreeMap;
+
+use cosmwasm_std::{Addr, Binary, Coin, Timestamp, Uint128};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex};
+use cw_utils::Expiration;
+use osmosis_std::types::osmosis::poolmanager::v1beta1::SwapAmountInRoute;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::msg::SwapResponse;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct Config {
-    pub owner: Addr,
+    /// `None` once ownership has been renounced.
+    pub owner: Option<Addr>,
+    pub affiliate_addr: Addr,
+    pub affiliate_bps: u16,
+    /// Derive the fee from `affiliate_bps` instead of trusting the caller-supplied net input.
+    #[serde(default)]
+    pub enforce_affiliate_bps: bool,
+    /// Reject denoms the execute path does not consume instead of refunding them.
+    #[serde(default)]
+    pub strict_funds: bool,
+    #[serde(default)]
+    pub affiliate_recipients: Vec<FeeShare>,
+    #[serde(default)]
+    pub affiliate_fee_side: FeeSide,
+    /// May pause operations alongside the owner, but not unpause them.
+    #[serde(default)]
+    pub guardian: Option<Addr>,
+    /// Credit fees to `ACCRUED_FEES` for later claiming instead of sending them per swap.
+    #[serde(default)]
+    pub accrue_fees: bool,
+    /// Slippage tolerated below the poolmanager estimate when converting accrued fees.
+    #[serde(default = "default_conversion_max_slippage_bps")]
+    pub conversion_max_slippage_bps: u16,
+    /// Measure swap output from the contract's balance and cross-check it against the
+    /// poolmanager response.
+    #[serde(default)]
+    pub balance_delta_accounting: bool,
+    #[serde(default)]
+    pub twap_guard: Option<TwapGuard>,
+}
+
+/// Rejects exact-in swaps whose `token_out_min_amount` is too far below the output
+/// implied by the route's arithmetic TWAPs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct TwapGuard {
+    /// Tolerated shortfall of the minimum output below the TWAP-implied output.
+    pub max_slippage_bps: u16,
+    /// Length of the TWAP window ending at the current block.
+    pub window_seconds: u64,
+}
+
+pub const DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS: u16 = 100;
+
+fn default_conversion_max_slippage_bps() -> u16 {
+    DEFAULT_CONVERSION_MAX_SLIPPAGE_BPS
+}
+
+impl Config {
+    /// Affiliate paid when a swap does not name a registered partner.
+    pub fn default_affiliate(&self) -> AffiliateInfo {
+        AffiliateInfo {
+            affiliate_addr: self.affiliate_addr.clone(),
+            affiliate_bps: self.affiliate_bps,
+            recipients: self.affiliate_recipients.clone(),
+            fee_side: self.affiliate_fee_side.clone(),
+        }
+    }
+}
+
+/// Which side of the swap the affiliate fee is taken from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeSide {
+    #[default]
+    Input,
+    Output,
+}
+
+/// A recipient's share of the affiliate fee, out of 10_000.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeeShare {
+    pub addr: Addr,
+    pub weight: u16,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct AffiliateInfo {
     pub affiliate_addr: Addr,
     pub affiliate_bps: u16,
+    /// Weighted fee split; when empty the whole fee goes to `affiliate_addr`.
+    #[serde(default)]
+    pub recipients: Vec<FeeShare>,
+    #[serde(default)]
+    pub fee_side: FeeSide,
+}
+
+impl AffiliateInfo {
+    pub fn shares(&self) -> Vec<FeeShare> {
+        if self.recipients.is_empty() {
+            vec![FeeShare {
+                addr: self.affiliate_addr.clone(),
+                weight: 10_000,
+            }]
+        } else {
+            self.recipients.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct FeePayout {
+    pub recipient: Addr,
+    pub amount: Coin,
+}
+
+/// Operation groups that can be paused independently.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PausableOperation {
+    Swaps,
+    Config,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, Default)]
+pub struct PauseState {
+    pub swaps: bool,
+    pub config: bool,
+}
+
+impl PauseState {
+    pub fn is_paused(&self, op: PausableOperation) -> bool {
+        match op {
+            PausableOperation::Swaps => self.swaps,
+            PausableOperation::Config => self.config,
+        }
+    }
+
+    pub fn set(&mut self, op: PausableOperation, paused: bool) {
+        match op {
+            PausableOperation::Swaps => self.swaps = paused,
+            PausableOperation::Config => self.config = paused,
+        }
+    }
+}
+
+/// Owner-managed filters applied to every hop of a proxied swap. An empty allow list
+/// allows everything not denied.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema, Default)]
+pub struct RouteLists {
+    pub allowed_pools: Vec<u64>,
+    pub denied_pools: Vec<u64>,
+    pub allowed_denoms: Vec<String>,
+    pub denied_denoms: Vec<String>,
+}
+
+impl RouteLists {
+    pub fn pool_allowed(&self, pool_id: u64) -> bool {
+        !self.denied_pools.contains(&pool_id)
+            && (self.allowed_pools.is_empty() || self.allowed_pools.contains(&pool_id))
+    }
+
+    pub fn denom_allowed(&self, denom: &str) -> bool {
+        !self.denied_denoms.iter().any(|d| d == denom)
+            && (self.allowed_denoms.is_empty() || self.allowed_denoms.iter().any(|d| d == denom))
+    }
+}
+
+/// Ownership transfer awaiting acceptance by `new_owner`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingOwnership {
+    pub new_owner: Addr,
+    pub expiry: Option<Expiration>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub enum PendingSwapKind {
     Single,
     Split,
+    /// Output amount is fixed by the request; the reply reports the input spent.
+    ExactOut {
+        token_out_amount: Uint128,
+    },
+    SplitExactOut {
+        token_out_amount: Uint128,
+    },
+}
+
+/// Affiliate fee to take from the swap output, for `FeeSide::Output` affiliates.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct OutputFee {
+    pub affiliate_bps: u16,
+    pub recipients: Vec<FeeShare>,
+    /// Minimum the user must receive after the fee.
+    pub token_out_min_amount: Uint128,
+}
+
+/// ICS-20 transfer of the swap output, resolved when the swap is dispatched.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct IbcForwardState {
+    pub channel: String,
+    pub receiver: String,
+    /// Absolute timeout in nanoseconds since the unix epoch.
+    pub timeout_timestamp: u64,
+    pub memo: String,
+}
+
+/// Outbound transfer whose funds are credited to `recovery_addr` if it fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct InFlightTransfer {
+    pub channel: String,
+    pub recovery_addr: Addr,
+    pub amount: Coin,
+}
+
+/// Contract executed with the swap output as funds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct WasmCallback {
+    pub contract_addr: Addr,
+    pub msg: Binary,
+}
+
+/// Output handed to a post-swap callback, refunded to `refund_addr` if the callback fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PendingCallback {
+    pub refund_addr: Addr,
+    pub amount: Coin,
+}
+
+/// Swaps dispatched by one `BatchProxySwap`, paid out together when the last leg replies.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchState {
+    pub recipient: Addr,
+    /// Legs still awaiting their reply.
+    pub pending: u32,
+    /// Outputs and exact-out refunds collected so far, per denom.
+    pub payout: BTreeMap<String, Uint128>,
+    pub legs: Vec<SwapResponse>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct SwapReplyState {
     pub original_sender: Addr,
+    /// Affiliate charged for this swap, recorded in the swap history.
+    pub affiliate_addr: Addr,
+    pub affiliate_id: Option<String>,
+    /// Receives the swap output; unused input still goes back to `original_sender`.
+    pub recipient: Addr,
+    pub token_in_denom: String,
+    pub token_in_amount: Uint128,
     pub token_out_denom: String,
     pub kind: PendingSwapKind,
+    pub affiliate_payouts: Vec<FeePayout>,
+    pub output_fee: Option<OutputFee>,
+    /// Sends the output over IBC; `recipient` is credited if the transfer fails.
+    pub ibc_forward: Option<IbcForwardState>,
+    /// Delivers the output to a contract; `recipient` is refunded if it fails.
+    pub post_swap_action: Option<WasmCallback>,
+    /// Batch this swap belongs to; its output is paid out with the rest of the batch.
+    pub batch: Option<u64>,
+    /// Contract balance of the output denom just before the swap runs, with balance-delta
+    /// accounting enabled.
+    #[serde(default)]
+    pub token_out_balance_before: Option<Uint128>,
+}
+
+/// Running totals for one denom.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct DenomStats {
+    /// Affiliate fees charged in this denom.
+    pub fees_collected: Uint128,
+    /// Swap input spent in this denom, excluding fees.
+    pub volume: Uint128,
+}
+
+/// Completed swap, as kept in the swap history.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct SwapRecord {
+    pub id: u64,
+    pub sender: Addr,
+    pub affiliate: Addr,
+    pub affiliate_id: Option<String>,
+    /// Input actually spent by the pool, excluding the affiliate fee.
+    pub token_in: Coin,
+    /// Output delivered to the user, after any output-side fee.
+    pub token_out: Coin,
+    /// In the input denom for input-side fees, in the output denom otherwise.
+    pub affiliate_fee: Coin,
+    pub height: u64,
+    pub time: Timestamp,
+}
+
+pub struct SwapRecordIndexes<'a> {
+    pub sender: MultiIndex<'a, Addr, SwapRecord, u64>,
+    pub affiliate: MultiIndex<'a, Addr, SwapRecord, u64>,
+}
+
+impl<'a> IndexList<SwapRecord> for SwapRecordIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<SwapRecord>> + '_> {
+        let v: Vec<&dyn Index<SwapRecord>> = vec![&self.sender, &self.affiliate];
+        Box::new(v.into_iter())
+    }
+}
+
+/// Accrued fees of `denom_in` swapped into `denom_out`, awaiting the swap reply.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct PendingConversion {
+    pub denom_in: String,
+    pub denom_out: String,
+    /// Balances debited per recipient; the output is credited back pro rata.
+    pub shares: Vec<(Addr, Uint128)>,
+}
+
+/// Swap history keyed by record id, indexed by sender and affiliate address.
+pub fn swap_history<'a>() -> IndexedMap<'a, u64, SwapRecord, SwapRecordIndexes<'a>> {
+    let indexes = SwapRecordIndexes {
+        sender: MultiIndex::new(
+            |_pk, r: &SwapRecord| r.sender.clone(),
+            "swap_history",
+            "swap_history__sender",
+        ),
+        affiliate: MultiIndex::new(
+            |_pk, r: &SwapRecord| r.affiliate.clone(),
+            "swap_history",
+            "swap_history__affiliate",
+        ),
+    };
+    IndexedMap::new("swap_history", indexes)
 }
 
 pub const CONFIG: Item<Config> = Item::new("config");
-pub const SWAP_REPLY_STATE: Item<SwapReplyState> = Item::new("swap_reply_state");
+/// Registered partners keyed by affiliate id.
+pub const AFFILIATES: Map<String, AffiliateInfo> = Map::new("affiliates");
+/// Affiliate bps charged to specific senders instead of the affiliate's own; 0 exempts them.
+pub const FEE_OVERRIDES: Map<&Addr, u16> = Map::new("fee_overrides");
+pub const PAUSE_STATE: Item<PauseState> = Item::new("pause_state");
+pub const ROUTE_LISTS: Item<RouteLists> = Item::new("route_lists");
+pub const PENDING_OWNERSHIP: Item<PendingOwnership> = Item::new("pending_ownership");
+/// Claimable fee balances per recipient and denom, in accrual mode.
+pub const ACCRUED_FEES: Map<(&Addr, &str), Uint128> = Map::new("accrued_fees");
+/// Address allowed to claim a fee recipient's accrued fees besides the recipient itself.
+pub const FEE_WITHDRAW_ADDRS: Map<&Addr, Addr> = Map::new("fee_withdraw_addrs");
+/// Denom a fee recipient wants its accrued fees converted into.
+pub const SETTLEMENT_DENOMS: Map<&Addr, String> = Map::new("settlement_denoms");
+/// Owner-configured routes used to convert accrued fees, keyed by (denom in, denom out).
+pub const CONVERSION_ROUTES: Map<(&str, &str), Vec<SwapAmountInRoute>> =
+    Map::new("conversion_routes");
+pub const AFFILIATE_STATS: Map<(&Addr, &str), DenomStats> = Map::new("affiliate_stats");
+pub const GLOBAL_STATS: Map<&str, DenomStats> = Map::new("global_stats");
+/// Id of the last swap record written.
+pub const SWAP_RECORD_COUNT: Item<u64> = Item::new("swap_record_count");
+/// Last nonce handed out for reply ids; see `execute::next_reply_nonce`.
+pub const REPLY_NONCE: Item<u64> = Item::new("reply_nonce");
+/// In-flight swaps keyed by the nonce in their reply id.
+pub const SWAP_REPLY_STATES: Map<u64, SwapReplyState> = Map::new("swap_reply_states");
+/// In-flight batches keyed by the nonce reserved for them.
+pub const BATCH_STATES: Map<u64, BatchState> = Map::new("batch_states");
+/// Transfers dispatched in the current tx, until their reply reports the sequence.
+pub const PENDING_IBC_TRANSFERS: Map<u64, InFlightTransfer> = Map::new("pending_ibc_transfers");
+pub const PENDING_CALLBACKS: Map<u64, PendingCallback> = Map::new("pending_callbacks");
+pub const PENDING_CONVERSIONS: Map<u64, PendingConversion> = Map::new("pending_conversions");
+/// Transfers awaiting ack or timeout, keyed by (source channel, sequence).
+pub const IBC_TRANSFERS: Map<(&str, u64), InFlightTransfer> = Map::new("ibc_transfers");
+/// Funds from failed transfers claimable by an address, per denom.
+pub const RECOVERABLE: Map<(&Addr, &str), Uint128> = Map::new("recoverable");